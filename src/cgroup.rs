@@ -1,20 +1,130 @@
+use std::collections::BTreeSet;
+use std::fmt::{Display, Formatter};
 use std::fs::{create_dir_all, read, remove_dir, File};
 use std::io::Write as _;
 use std::os::unix::fs::OpenOptionsExt;
-use std::path::{Path, PathBuf};
-use std::time::Duration;
+use std::path::{Component, Path, PathBuf};
+use std::str::FromStr;
+use std::sync::OnceLock;
+use std::time::{Duration, Instant};
 
-use crate::{Error, Pid};
+use nix::sys::statfs::{statfs, CGROUP2_SUPER_MAGIC};
 
+use crate::{Error, MountInfo, Pid};
+
+/// Returns cgroup v2 path of current process relative to cgroup namespace root.
+fn current_cgroup_path() -> Result<String, Error> {
+    for line in String::from_utf8(read(PROC_CGROUP)?)?.split('\n') {
+        let parts: Vec<_> = line.split(':').collect();
+        if let Some(v) = parts.get(1) {
+            if !v.is_empty() {
+                continue;
+            }
+        }
+        return Ok(parts.get(2).ok_or("Expected cgroup path")?.to_string());
+    }
+    Err("Cannot resolve cgroup".into())
+}
+
+/// Returns root and mount point of cgroup2 mount of current process.
+fn cgroup2_mount() -> Result<Option<(PathBuf, PathBuf)>, Error> {
+    Ok(MountInfo::read(PROC_MOUNTINFO)?
+        .into_iter()
+        .find(|v| v.fstype == "cgroup2")
+        .map(|v| (v.root, v.mount_point)))
+}
+
+/// Verifies that cgroup name does not escape parent cgroup.
+fn check_name(name: &Path) -> Result<(), Error> {
+    for component in name.components() {
+        match component {
+            Component::Normal(_) => {}
+            Component::CurDir | Component::ParentDir => Err(format!(
+                "Cgroup name cannot contain {component:?}: {name:?}"
+            ))?,
+            _ => Err(format!("Invalid cgroup name: {name:?}"))?,
+        }
+    }
+    Ok(())
+}
+
+/// Represents cgroup v2 directory.
+///
+/// Cgroups are equal when they have same mount path and same name relative to
+/// it. Both are compared by normalized components, so trailing and repeated
+/// separators are ignored. Comparison is structural: symlinks are not resolved
+/// and same cgroup seen through different mounts is not equal.
 #[derive(Clone, Debug)]
 pub struct Cgroup {
     mount_path: PathBuf,
     path: PathBuf,
 }
 
+impl PartialEq for Cgroup {
+    fn eq(&self, other: &Self) -> bool {
+        self.mount_path
+            .components()
+            .eq(other.mount_path.components())
+            && self.name().components().eq(other.name().components())
+    }
+}
+
+impl Eq for Cgroup {}
+
 const PROC_CGROUP: &str = "/proc/self/cgroup";
+const PROC_MOUNTINFO: &str = "/proc/self/mountinfo";
 const CGROUP_MOUNT: &str = "/sys/fs/cgroup";
 const CGROUP_PROCS: &str = "cgroup.procs";
+const CGROUP_THREADS: &str = "cgroup.threads";
+const CGROUP_TYPE: &str = "cgroup.type";
+/// Maximum time for all processes of cgroup to become frozen.
+const FREEZE_TIMEOUT: Duration = Duration::from_secs(10);
+/// Maximum time for all processes of cgroup to exit after kill.
+const KILL_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Represents cgroup hierarchy mode of system.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CgroupMode {
+    /// Only cgroup v2 is mounted at `/sys/fs/cgroup`.
+    Unified,
+    /// Cgroup v1 controllers are mounted at `/sys/fs/cgroup` and cgroup v2
+    /// without controllers is mounted at `/sys/fs/cgroup/unified`.
+    Hybrid,
+    /// Only cgroup v1 is mounted.
+    Legacy,
+}
+
+impl CgroupMode {
+    /// Detects cgroup hierarchy mode using filesystem type of `/sys/fs/cgroup`.
+    pub fn detect() -> Result<Self, Error> {
+        let fs_type = statfs(CGROUP_MOUNT)
+            .map_err(|v| format!("Cannot statfs {CGROUP_MOUNT:?}: {v}"))?
+            .filesystem_type();
+        if fs_type == CGROUP2_SUPER_MAGIC {
+            return Ok(Self::Unified);
+        }
+        match statfs(&Path::new(CGROUP_MOUNT).join("unified")) {
+            Ok(v) if v.filesystem_type() == CGROUP2_SUPER_MAGIC => Ok(Self::Hybrid),
+            _ => Ok(Self::Legacy),
+        }
+    }
+}
+
+/// Checks that system runs unified cgroup hierarchy.
+///
+/// Detection runs once, systems without `/sys/fs/cgroup` are not checked.
+fn check_unified() -> Result<(), Error> {
+    static MODE: OnceLock<Option<CgroupMode>> = OnceLock::new();
+    let system = match MODE.get_or_init(|| CgroupMode::detect().ok()) {
+        Some(CgroupMode::Unified) | None => return Ok(()),
+        Some(CgroupMode::Hybrid) => "hybrid cgroup v1 and v2",
+        Some(CgroupMode::Legacy) => "cgroup v1",
+    };
+    Err(format!(
+        "sbox requires cgroup v2 (unified hierarchy); system is running {system}, boot with systemd.unified_cgroup_hierarchy=1"
+    )
+    .into())
+}
 
 impl Cgroup {
     pub fn new(mount_path: impl Into<PathBuf>, name: impl AsRef<Path>) -> Result<Self, Error> {
@@ -22,10 +132,14 @@ impl Cgroup {
         if name.is_absolute() {
             Err("Cgroup name cannot be absolute")?
         }
+        check_name(name)?;
         let mount_path = mount_path.into();
         if !mount_path.is_absolute() {
             Err("Cgroup mount path should be absolute")?
         }
+        if mount_path == Path::new(CGROUP_MOUNT) {
+            check_unified()?;
+        }
         let path = mount_path.join(name);
         Ok(Self { mount_path, path })
     }
@@ -44,19 +158,42 @@ impl Cgroup {
         &self.mount_path
     }
 
+    /// Returns cgroup of current process.
+    ///
+    /// Inside cgroup namespace `/proc/self/cgroup` reports path relative to
+    /// namespace root, so path is resolved using root of cgroup2 mount from
+    /// `/proc/self/mountinfo`.
     pub fn current() -> Result<Self, Error> {
-        for line in String::from_utf8(read(PROC_CGROUP)?)?.split('\n') {
-            let parts: Vec<_> = line.split(':').collect();
-            if let Some(v) = parts.get(1) {
-                if !v.is_empty() {
-                    continue;
-                }
+        let path = current_cgroup_path()?;
+        let (root, mount_path) = match cgroup2_mount()? {
+            Some(v) => v,
+            None => return Cgroup::new(CGROUP_MOUNT, path.trim_start_matches('/')),
+        };
+        if let Ok(name) = Path::new(&path).strip_prefix(&root) {
+            return Cgroup::new(mount_path, name);
+        }
+        // Mount root is outside of cgroup namespace root, so the real path of
+        // namespace root is unknown and cgroup is looked up by current pid.
+        let depth = root.components().count() - 1;
+        if !root.components().skip(1).all(|v| v == Component::ParentDir) {
+            Err(format!(
+                "Cgroup {path:?} is not visible under cgroup2 mount {mount_path:?}"
+            ))?
+        }
+        let depth = depth + Path::new(&path).components().count() - 1;
+        let pid = Pid::this();
+        let mut cgroups = vec![Cgroup::new(mount_path, "")?];
+        for _ in 0..depth {
+            let mut next = Vec::new();
+            for cgroup in cgroups {
+                next.extend(cgroup.children()?);
+            }
+            cgroups = next;
+        }
+        for cgroup in cgroups {
+            if cgroup.name().ends_with(path.trim_start_matches('/')) && cgroup.has_process(pid)? {
+                return Ok(cgroup);
             }
-            let cgroup = parts
-                .get(2)
-                .ok_or("Expected cgroup path")?
-                .trim_start_matches('/');
-            return Cgroup::new(CGROUP_MOUNT, cgroup);
         }
         Err("Cannot resolve cgroup".into())
     }
@@ -77,11 +214,106 @@ impl Cgroup {
         if name.is_absolute() {
             Err("Child cgroup name cannot be absolute")?
         }
+        check_name(name)?;
         let mount_path = self.mount_path.clone();
         let path = self.path.join(name);
         Ok(Self { mount_path, path })
     }
 
+    /// Checks that other cgroup is located strictly under current cgroup.
+    pub fn is_ancestor_of(&self, other: &Cgroup) -> bool {
+        self.mount_path == other.mount_path
+            && self.path != other.path
+            && other.path.starts_with(&self.path)
+    }
+
+    /// Returns path of current cgroup relative to base cgroup.
+    ///
+    /// Returns `None` if current cgroup is not located under base cgroup.
+    pub fn relative_to(&self, base: &Cgroup) -> Option<PathBuf> {
+        if self.mount_path != base.mount_path {
+            return None;
+        }
+        Some(self.path.strip_prefix(&base.path).ok()?.to_owned())
+    }
+
+    /// Returns immediate child cgroups.
+    pub fn children(&self) -> Result<Vec<Cgroup>, Error> {
+        let mut children = Vec::new();
+        for entry in std::fs::read_dir(&self.path)? {
+            let entry = entry?;
+            if !entry.file_type()?.is_dir() {
+                continue;
+            }
+            let mount_path = self.mount_path.clone();
+            let path = entry.path();
+            children.push(Self { mount_path, path });
+        }
+        Ok(children)
+    }
+
+    fn has_process(&self, pid: Pid) -> Result<bool, Error> {
+        Ok(self.processes()?.contains(&pid))
+    }
+
+    /// Returns processes that are members of cgroup.
+    pub fn processes(&self) -> Result<Vec<Pid>, Error> {
+        let procs = String::from_utf8(read(self.path.join(CGROUP_PROCS))?)?;
+        let mut pids = Vec::new();
+        for line in procs.lines() {
+            pids.push(Pid::from_raw(line.parse()?));
+        }
+        Ok(pids)
+    }
+
+    /// Returns processes that are members of cgroup or its descendants.
+    pub fn processes_recursive(&self) -> Result<Vec<Pid>, Error> {
+        let mut pids = self.processes()?;
+        for child in self.children()? {
+            pids.extend(child.processes_recursive()?);
+        }
+        Ok(pids)
+    }
+
+    /// Kills all processes of cgroup and its descendants with `SIGKILL`.
+    ///
+    /// Uses `cgroup.kill` that requires Linux 5.14+, on older kernels
+    /// processes are killed one by one. Waits until cgroup becomes empty, but
+    /// exited processes are not reaped.
+    pub fn kill(&self) -> Result<(), Error> {
+        // Some kernels kill processes cloned into cgroup with CLONE_INTO_CGROUP
+        // after any write to cgroup.kill, so empty cgroup is not killed.
+        if self.processes_recursive()?.is_empty() {
+            return Ok(());
+        }
+        let killed = match std::fs::write(self.path.join("cgroup.kill"), "1") {
+            Ok(()) => true,
+            Err(v) if v.kind() == std::io::ErrorKind::NotFound => false,
+            Err(v) => return Err(format!("Cannot kill cgroup {:?}: {v}", self.path).into()),
+        };
+        let deadline = Instant::now() + KILL_TIMEOUT;
+        loop {
+            let pids = self.processes_recursive()?;
+            if pids.is_empty() {
+                return Ok(());
+            }
+            if !killed {
+                for pid in pids {
+                    match nix::sys::signal::kill(pid, nix::sys::signal::SIGKILL) {
+                        Ok(()) | Err(nix::errno::Errno::ESRCH) => {}
+                        Err(v) => return Err(format!("Cannot kill process {pid}: {v}").into()),
+                    }
+                }
+            }
+            if Instant::now() >= deadline {
+                return Err(
+                    format!("Cannot kill processes of cgroup {:?} in time", self.path).into(),
+                );
+            }
+            std::thread::sleep(Duration::from_millis(1));
+        }
+    }
+
     pub fn create(&self) -> Result<(), Error> {
         Ok(create_dir_all(&self.path)?)
     }
@@ -90,6 +322,16 @@ impl Cgroup {
         Ok(remove_dir(&self.path)?)
     }
 
+    /// Removes cgroup with all its descendants.
+    ///
+    /// All cgroups in subtree should not have processes.
+    pub fn remove_recursive(&self) -> Result<(), Error> {
+        for child in self.children()? {
+            child.remove_recursive()?;
+        }
+        self.remove()
+    }
+
     pub fn add_process(&self, pid: Pid) -> Result<(), Error> {
         File::options()
             .create(false)
@@ -100,6 +342,110 @@ impl Cgroup {
         Ok(())
     }
 
+    /// Moves process from specified cgroup and verifies that it left source.
+    ///
+    /// Useful for evacuating processes from cgroup before enabling subtree
+    /// controllers, since cgroup with enabled controllers cannot contain
+    /// processes.
+    pub fn move_process_from(&self, pid: Pid, from: &Cgroup) -> Result<(), Error> {
+        if !from.has_process(pid)? {
+            return Err(format!("Process {pid} is not in cgroup {:?}", from.path).into());
+        }
+        let result = File::options()
+            .create(false)
+            .write(true)
+            .truncate(false)
+            .open(self.path.join(CGROUP_PROCS))
+            .and_then(|mut v| v.write_all(pid.to_string().as_bytes()));
+        if let Err(err) = result {
+            let reason = match err.raw_os_error() {
+                Some(nix::libc::EOPNOTSUPP) => "domain and threaded cgroup types mismatch".into(),
+                Some(nix::libc::EBUSY) => "target cgroup has enabled subtree controllers".into(),
+                Some(nix::libc::EINVAL) => "process is kernel thread or cannot be moved".into(),
+                Some(nix::libc::ESRCH) => "process does not exist".into(),
+                _ => err.to_string(),
+            };
+            return Err(format!("Cannot move process {pid} to {:?}: {reason}", self.path).into());
+        }
+        if from.has_process(pid)? {
+            return Err(format!("Process {pid} was not moved from {:?}", from.path).into());
+        }
+        Ok(())
+    }
+
+    /// Adds thread to threaded cgroup.
+    pub fn add_thread(&self, tid: Pid) -> Result<(), Error> {
+        File::options()
+            .create(false)
+            .write(true)
+            .truncate(false)
+            .open(self.path.join(CGROUP_THREADS))?
+            .write_all(tid.to_string().as_bytes())?;
+        Ok(())
+    }
+
+    /// Reads cgroup type (`domain`, `threaded`, `domain threaded` or `domain invalid`).
+    pub fn cgroup_type(&self) -> Result<String, Error> {
+        let content = std::fs::read_to_string(self.path.join(CGROUP_TYPE))?;
+        Ok(content.trim_end().to_owned())
+    }
+
+    /// Turns cgroup into threaded mode.
+    ///
+    /// Processes of threaded cgroup should be added using `add_thread`.
+    pub fn set_threaded(&self) -> Result<(), Error> {
+        File::options()
+            .create(false)
+            .write(true)
+            .open(self.path.join(CGROUP_TYPE))?
+            .write_all(b"threaded")?;
+        Ok(())
+    }
+
+    /// Checks that cgroup is frozen by itself or by one of its ancestors.
+    pub fn is_frozen(&self) -> Result<bool, Error> {
+        let content = std::fs::read(self.path.join("cgroup.events"))?;
+        for line in content.split(|c| *c == b'\n').filter(|v| !v.is_empty()) {
+            if let Some(("frozen", value)) = std::str::from_utf8(line)?.split_once(' ') {
+                return Ok(value.trim_end() == "1");
+            }
+        }
+        Ok(false)
+    }
+
+    /// Freezes all processes of cgroup and its descendants.
+    ///
+    /// Waits until all processes are stopped.
+    pub fn freeze(&self) -> Result<(), Error> {
+        self.set_frozen(true)
+    }
+
+    /// Thaws processes frozen by `Cgroup::freeze`.
+    ///
+    /// Processes stay frozen if one of ancestors is frozen.
+    pub fn thaw(&self) -> Result<(), Error> {
+        self.set_frozen(false)
+    }
+
+    fn set_frozen(&self, frozen: bool) -> Result<(), Error> {
+        File::options()
+            .create(false)
+            .write(true)
+            .open(self.path.join("cgroup.freeze"))?
+            .write_all(if frozen { b"1" } else { b"0" })?;
+        if !frozen {
+            return Ok(());
+        }
+        let deadline = Instant::now() + FREEZE_TIMEOUT;
+        while !self.is_frozen()? {
+            if Instant::now() >= deadline {
+                return Err(format!("Cannot freeze cgroup {:?} in time", self.path).into());
+            }
+            std::thread::sleep(Duration::from_millis(1));
+        }
+        Ok(())
+    }
+
     /// Reads current memory usage.
     pub fn memory_current(&self) -> Result<usize, Error> {
         let content = std::fs::read_to_string(self.path.join("memory.current"))?;
@@ -112,6 +458,33 @@ impl Cgroup {
         Ok(content.trim_end().parse()?)
     }
 
+    /// Reads peak memory usage of cgroup and all descendant cgroups.
+    ///
+    /// Returns maximum of peak values of the whole subtree.
+    pub fn memory_peak_recursive(&self) -> Result<usize, Error> {
+        let mut peak = self.memory_peak()?;
+        for child in self.memory_children()? {
+            peak = peak.max(child.memory_peak_recursive()?);
+        }
+        Ok(peak)
+    }
+
+    /// Reads current memory usage of cgroup and all descendant cgroups.
+    ///
+    /// Alias of `memory_current`: usage of cgroup v2 is hierarchical, so
+    /// `memory.current` already includes usage of descendants.
+    pub fn memory_current_recursive(&self) -> Result<usize, Error> {
+        self.memory_current()
+    }
+
+    /// Returns child cgroups with enabled memory controller.
+    fn memory_children(&self) -> Result<Vec<Cgroup>, Error> {
+        if !self.subtree_controller_set()?.contains("memory") {
+            return Ok(Vec::new());
+        }
+        self.children()
+    }
+
     pub fn memory_events(&self) -> Result<CgroupMemoryEvents, Error> {
         let content = std::fs::read(self.path.join("memory.events"))?;
         let mut events = CgroupMemoryEvents::default();
@@ -142,6 +515,44 @@ impl Cgroup {
         Ok(())
     }
 
+    /// Removes memory limit by writing `max` to `memory.max`.
+    pub fn clear_memory_limit(&self) -> Result<(), Error> {
+        File::options()
+            .create(false)
+            .write(true)
+            .open(self.path.join("memory.max"))?
+            .write_all(b"max")?;
+        Ok(())
+    }
+
+    /// Applies memory limit with specified behavior on reaching it.
+    ///
+    /// Memory knobs not used by policy are reset, so policies can be switched.
+    pub fn set_oom_policy(&self, policy: OomPolicy) -> Result<(), Error> {
+        let (max, high, group) = match policy {
+            OomPolicy::Kill(bytes) => (Some(bytes), None, false),
+            OomPolicy::Throttle(bytes) => (None, Some(bytes), false),
+            OomPolicy::GroupKill(bytes) => (Some(bytes), None, true),
+        };
+        let format_limit = |v: Option<usize>| v.map_or("max".to_owned(), |v| v.to_string());
+        File::options()
+            .create(false)
+            .write(true)
+            .open(self.path.join("memory.oom.group"))?
+            .write_all(if group { b"1" } else { b"0" })?;
+        File::options()
+            .create(false)
+            .write(true)
+            .open(self.path.join("memory.high"))?
+            .write_all(format_limit(high).as_bytes())?;
+        File::options()
+            .create(false)
+            .write(true)
+            .open(self.path.join("memory.max"))?
+            .write_all(format_limit(max).as_bytes())?;
+        Ok(())
+    }
+
     pub fn set_memory_guarantee(&self, bytes: usize) -> Result<(), Error> {
         File::options()
             .create(false)
@@ -151,6 +562,58 @@ impl Cgroup {
         Ok(())
     }
 
+    /// Sets memory throttling threshold (`memory.high`) in bytes.
+    pub fn set_memory_high(&self, bytes: usize) -> Result<(), Error> {
+        File::options()
+            .create(false)
+            .write(true)
+            .open(self.path.join("memory.high"))?
+            .write_all(format!("{}", bytes).as_bytes())?;
+        Ok(())
+    }
+
+    /// Sets best-effort memory protection (`memory.low`) in bytes.
+    pub fn set_memory_low(&self, bytes: usize) -> Result<(), Error> {
+        File::options()
+            .create(false)
+            .write(true)
+            .open(self.path.join("memory.low"))?
+            .write_all(format!("{}", bytes).as_bytes())?;
+        Ok(())
+    }
+
+    /// Returns memory guarantee (`memory.min`) in bytes.
+    pub fn memory_min(&self) -> Result<Option<usize>, Error> {
+        self.read_memory_threshold("memory.min")
+    }
+
+    /// Returns best-effort memory protection (`memory.low`) in bytes.
+    pub fn memory_low(&self) -> Result<Option<usize>, Error> {
+        self.read_memory_threshold("memory.low")
+    }
+
+    /// Returns memory throttling threshold (`memory.high`) in bytes.
+    ///
+    /// Returns `None` if threshold is not set.
+    pub fn memory_high(&self) -> Result<Option<usize>, Error> {
+        self.read_memory_threshold("memory.high")
+    }
+
+    /// Returns memory limit (`memory.max`) in bytes.
+    ///
+    /// Returns `None` if limit is not set.
+    pub fn memory_max(&self) -> Result<Option<usize>, Error> {
+        self.read_memory_threshold("memory.max")
+    }
+
+    fn read_memory_threshold(&self, name: &str) -> Result<Option<usize>, Error> {
+        let content = std::fs::read_to_string(self.path.join(name))?;
+        match content.trim_end() {
+            "max" => Ok(None),
+            v => Ok(Some(v.parse()?)),
+        }
+    }
+
     pub fn set_swap_memory_limit(&self, limit: usize) -> Result<(), Error> {
         File::options()
             .create(false)
@@ -172,6 +635,11 @@ impl Cgroup {
                 "usage_usec" => usage.total = Duration::from_micros(value.trim_end().parse()?),
                 "user_usec" => usage.user = Duration::from_micros(value.trim_end().parse()?),
                 "system_usec" => usage.system = Duration::from_micros(value.trim_end().parse()?),
+                "nr_periods" => usage.nr_periods = value.trim_end().parse()?,
+                "nr_throttled" => usage.nr_throttled = value.trim_end().parse()?,
+                "throttled_usec" => {
+                    usage.throttled_time = Duration::from_micros(value.trim_end().parse()?)
+                }
                 _ => continue,
             }
         }
@@ -187,6 +655,16 @@ impl Cgroup {
         Ok(())
     }
 
+    /// Removes CPU quota by writing `max` to `cpu.max`, keeping current period.
+    pub fn clear_cpu_limit(&self) -> Result<(), Error> {
+        File::options()
+            .create(false)
+            .write(true)
+            .open(self.path.join("cpu.max"))?
+            .write_all(b"max")?;
+        Ok(())
+    }
+
     pub fn set_pids_limit(&self, limit: usize) -> Result<(), Error> {
         File::options()
             .create(false)
@@ -196,42 +674,147 @@ impl Cgroup {
         Ok(())
     }
 
-    pub fn controllers(&self) -> Result<Vec<String>, Error> {
-        let content = std::fs::read(self.path.join("cgroup.controllers"))?;
-        let mut controllers = Vec::new();
-        for line in content.split(|c| *c == b'\n').filter(|v| !v.is_empty()) {
-            std::str::from_utf8(line)?
-                .split(' ')
-                .for_each(|v| controllers.push(v.to_owned()));
+    /// Removes limit of processes by writing `max` to `pids.max`.
+    pub fn clear_pids_limit(&self) -> Result<(), Error> {
+        File::options()
+            .create(false)
+            .write(true)
+            .open(self.path.join("pids.max"))?
+            .write_all(b"max")?;
+        Ok(())
+    }
+
+    /// Reads default proportional I/O weight.
+    pub fn io_weight(&self) -> Result<u16, Error> {
+        let content = std::fs::read_to_string(self.path.join("io.weight"))
+            .map_err(|v| io_controller_error(v, "io.weight"))?;
+        for line in content.lines() {
+            if let Some(("default", value)) = line.split_once(' ') {
+                return Ok(value.trim_end().parse()?);
+            }
         }
-        Ok(controllers)
+        Err("Cannot find default io weight".into())
     }
 
-    pub fn subtree_controllers(&self) -> Result<Vec<String>, Error> {
-        let content = std::fs::read(self.path.join("cgroup.subtree_control"))?;
-        let mut controllers = Vec::new();
-        for line in content.split(|c| *c == b'\n').filter(|v| !v.is_empty()) {
-            std::str::from_utf8(line)?
-                .split(' ')
-                .for_each(|v| controllers.push(v.to_owned()));
+    /// Sets default proportional I/O weight in range from 1 to 10000.
+    pub fn set_io_weight(&self, weight: u16) -> Result<(), Error> {
+        if !(1..=10000).contains(&weight) {
+            return Err(format!("Invalid io weight: {weight}").into());
         }
-        Ok(controllers)
+        File::options()
+            .create(false)
+            .write(true)
+            .open(self.path.join("io.weight"))
+            .map_err(|v| io_controller_error(v, "io.weight"))?
+            .write_all(format!("default {weight}").as_bytes())?;
+        Ok(())
+    }
+
+    /// Sets I/O latency target for specified block device.
+    pub fn set_io_latency(&self, major: u32, minor: u32, target: Duration) -> Result<(), Error> {
+        File::options()
+            .create(false)
+            .write(true)
+            .open(self.path.join("io.latency"))
+            .map_err(|v| io_controller_error(v, "io.latency"))?
+            .write_all(format!("{major}:{minor} target={}", target.as_micros()).as_bytes())?;
+        Ok(())
+    }
+
+    pub fn controllers(&self) -> Result<Vec<String>, Error> {
+        Ok(self.controller_set()?.into())
     }
 
+    pub fn subtree_controllers(&self) -> Result<Vec<String>, Error> {
+        Ok(self.subtree_controller_set()?.into())
+    }
+
+    /// Returns controllers available in cgroup (`cgroup.controllers`).
+    pub fn controller_set(&self) -> Result<Controllers, Error> {
+        String::from_utf8(std::fs::read(self.path.join("cgroup.controllers"))?)?.parse()
+    }
+
+    /// Returns controllers enabled for child cgroups (`cgroup.subtree_control`).
+    pub fn subtree_controller_set(&self) -> Result<Controllers, Error> {
+        String::from_utf8(std::fs::read(self.path.join("cgroup.subtree_control"))?)?.parse()
+    }
+
+    /// Enables specified controllers for child cgroups.
     pub fn add_subtree_controllers(&self, controllers: Vec<String>) -> Result<(), Error> {
+        self.write_subtree_controllers('+', &controllers.into())
+    }
+
+    /// Disables specified controllers for child cgroups.
+    pub fn remove_subtree_controllers(&self, controllers: Vec<String>) -> Result<(), Error> {
+        self.write_subtree_controllers('-', &controllers.into())
+    }
+
+    fn write_subtree_controllers(&self, op: char, controllers: &Controllers) -> Result<(), Error> {
+        for name in controllers {
+            check_controller(name)?;
+        }
         File::options()
             .create(false)
             .write(true)
             .open(self.path.join("cgroup.subtree_control"))?
             .write_all(
                 controllers
-                    .into_iter()
-                    .fold(String::new(), |acc, v| acc + " +" + &v)
+                    .iter()
+                    .fold(String::new(), |acc, v| format!("{acc} {op}{v}"))
                     .as_bytes(),
             )?;
         Ok(())
     }
 
+    /// Applies specified resource limits.
+    ///
+    /// Required controllers are enabled in parent cgroup first. Returned error
+    /// describes limit that cannot be applied.
+    pub fn apply(&self, limits: &ResourceLimits) -> Result<(), Error> {
+        if let Some(parent) = self.parent() {
+            let enabled = parent.subtree_controller_set()?;
+            let missing = Controllers::from(limits.controllers()).difference(&enabled);
+            if !missing.is_empty() {
+                parent
+                    .write_subtree_controllers('+', &missing)
+                    .map_err(|v| format!("Cannot enable cgroup controllers \"{missing}\": {v}"))?;
+            }
+        }
+        if let Some(v) = limits.memory {
+            self.set_memory_limit(v)
+                .map_err(|e| format!("Cannot set memory limit: {e}"))?;
+        }
+        if let Some(v) = limits.memory_high {
+            self.set_memory_high(v)
+                .map_err(|e| format!("Cannot set memory high: {e}"))?;
+        }
+        if let Some(v) = limits.memory_low {
+            self.set_memory_low(v)
+                .map_err(|e| format!("Cannot set memory low: {e}"))?;
+        }
+        if let Some(v) = limits.memory_min {
+            self.set_memory_guarantee(v)
+                .map_err(|e| format!("Cannot set memory guarantee: {e}"))?;
+        }
+        if let Some(v) = limits.swap_memory {
+            self.set_swap_memory_limit(v)
+                .map_err(|e| format!("Cannot set swap memory limit: {e}"))?;
+        }
+        if let Some((limit, period)) = limits.cpu {
+            self.set_cpu_limit(limit, period)
+                .map_err(|e| format!("Cannot set cpu limit: {e}"))?;
+        }
+        if let Some(v) = limits.pids {
+            self.set_pids_limit(v)
+                .map_err(|e| format!("Cannot set pids limit: {e}"))?;
+        }
+        if let Some(v) = limits.io_weight {
+            self.set_io_weight(v)
+                .map_err(|e| format!("Cannot set io weight: {e}"))?;
+        }
+        Ok(())
+    }
+
     pub fn open(&self) -> Result<File, Error> {
         Ok(File::options()
             .read(true)
@@ -240,6 +823,143 @@ impl Cgroup {
     }
 }
 
+fn io_controller_error(err: std::io::Error, file: &str) -> Error {
+    if err.kind() == std::io::ErrorKind::NotFound {
+        format!("Cannot open {file}: io controller is not enabled").into()
+    } else {
+        err.into()
+    }
+}
+
+/// Represents set of cgroup controllers.
+///
+/// Formatted and parsed as space separated list of controller names, that is
+/// format of `cgroup.controllers` and `cgroup.subtree_control`.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Controllers(BTreeSet<String>);
+
+impl Controllers {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.0.contains(name)
+    }
+
+    /// Adds controller and returns whether it was not present.
+    pub fn insert(&mut self, name: impl Into<String>) -> bool {
+        self.0.insert(name.into())
+    }
+
+    /// Removes controller and returns whether it was present.
+    pub fn remove(&mut self, name: &str) -> bool {
+        self.0.remove(name)
+    }
+
+    /// Returns controllers present in either set.
+    pub fn union(&self, other: &Controllers) -> Self {
+        Self(self.0.union(&other.0).cloned().collect())
+    }
+
+    /// Returns controllers present in both sets.
+    pub fn intersection(&self, other: &Controllers) -> Self {
+        Self(self.0.intersection(&other.0).cloned().collect())
+    }
+
+    /// Returns controllers that are not present in other set.
+    pub fn difference(&self, other: &Controllers) -> Self {
+        Self(self.0.difference(&other.0).cloned().collect())
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &str> {
+        self.0.iter().map(String::as_str)
+    }
+}
+
+impl Display for Controllers {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        for (i, name) in self.0.iter().enumerate() {
+            if i > 0 {
+                f.write_str(" ")?;
+            }
+            f.write_str(name)?;
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for Controllers {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut controllers = Self::new();
+        for name in s.split_ascii_whitespace() {
+            check_controller(name)?;
+            controllers.insert(name);
+        }
+        Ok(controllers)
+    }
+}
+
+impl From<Vec<String>> for Controllers {
+    fn from(value: Vec<String>) -> Self {
+        Self(value.into_iter().collect())
+    }
+}
+
+impl From<Controllers> for Vec<String> {
+    fn from(value: Controllers) -> Self {
+        value.0.into_iter().collect()
+    }
+}
+
+impl<S: Into<String>> FromIterator<S> for Controllers {
+    fn from_iter<T: IntoIterator<Item = S>>(iter: T) -> Self {
+        Self(iter.into_iter().map(Into::into).collect())
+    }
+}
+
+impl<'a> IntoIterator for &'a Controllers {
+    type Item = &'a String;
+    type IntoIter = std::collections::btree_set::Iter<'a, String>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.0.iter()
+    }
+}
+
+/// Checks that controller name can be written to `cgroup.subtree_control`.
+fn check_controller(name: &str) -> Result<(), Error> {
+    if name.is_empty()
+        || !name
+            .bytes()
+            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == b'_')
+    {
+        Err(format!("Invalid cgroup controller name: {name:?}"))?
+    }
+    Ok(())
+}
+
+/// Represents behavior of cgroup on reaching memory limit in bytes.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OomPolicy {
+    /// OOM killer kills single process of cgroup (`memory.max`).
+    Kill(usize),
+    /// Processes are throttled and reclaimed without OOM kills (`memory.high`).
+    Throttle(usize),
+    /// OOM killer kills all processes of cgroup (`memory.max` with `memory.oom.group`).
+    GroupKill(usize),
+}
+
 #[derive(Clone, Copy, Debug, Default)]
 pub struct CgroupMemoryEvents {
     pub low: usize,
@@ -255,4 +975,60 @@ pub struct CgroupCpuUsage {
     pub total: Duration,
     pub user: Duration,
     pub system: Duration,
+    /// Number of elapsed enforcement periods of `cpu.max`.
+    pub nr_periods: u64,
+    /// Number of periods when cgroup was throttled after exhausting quota.
+    pub nr_throttled: u64,
+    /// Total time of cgroup being throttled.
+    pub throttled_time: Duration,
+}
+
+/// Represents resource limits for cgroup.
+#[derive(Clone, Debug, Default)]
+pub struct ResourceLimits {
+    /// Memory limit in bytes.
+    pub memory: Option<usize>,
+    /// Memory throttling threshold in bytes.
+    pub memory_high: Option<usize>,
+    /// Best-effort memory protection in bytes.
+    pub memory_low: Option<usize>,
+    /// Memory guarantee in bytes.
+    pub memory_min: Option<usize>,
+    /// Swap memory limit in bytes.
+    pub swap_memory: Option<usize>,
+    /// CPU time limit per period.
+    pub cpu: Option<(Duration, Duration)>,
+    /// Maximum amount of processes.
+    pub pids: Option<usize>,
+    /// IO weight in range from 1 to 10000.
+    pub io_weight: Option<u16>,
+}
+
+impl ResourceLimits {
+    pub fn is_empty(&self) -> bool {
+        self.controllers().is_empty()
+    }
+
+    /// Returns controllers required for applying limits.
+    pub fn controllers(&self) -> Vec<String> {
+        let mut controllers = Vec::new();
+        if self.memory.is_some()
+            || self.memory_high.is_some()
+            || self.memory_low.is_some()
+            || self.memory_min.is_some()
+            || self.swap_memory.is_some()
+        {
+            controllers.push("memory".to_owned());
+        }
+        if self.cpu.is_some() {
+            controllers.push("cpu".to_owned());
+        }
+        if self.pids.is_some() {
+            controllers.push("pids".to_owned());
+        }
+        if self.io_weight.is_some() {
+            controllers.push("io".to_owned());
+        }
+        controllers
+    }
 }
@@ -1,8 +1,25 @@
-use std::fs::create_dir_all;
-use std::path::PathBuf;
-use std::sync::Arc;
+use std::ffi::OsStr;
+use std::fs::{create_dir_all, remove_dir_all};
+use std::ops::BitOr;
+use std::os::unix::fs::MetadataExt;
+use std::panic::{catch_unwind, UnwindSafe};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
 
-use crate::{Cgroup, Mount, NetworkManager, UserMapper};
+use nix::errno::Errno;
+use nix::sched::CloneFlags;
+use nix::sys::signal::kill;
+use nix::sys::wait::{waitpid, WaitPidFlag};
+use nix::unistd::{fork, ForkResult, Uid};
+
+use crate::{
+    check_user_namespaces, exit_child, join_root, new_pipe, pidfd_open, read_result, run_as_root,
+    set_child_panic_hook, write_result, Cgroup, Controllers, GroupSource, HostUserMapper,
+    InitProcess, InitProcessOptions, Mount, MountInfo, NetworkManager, OwnedPid, Pid, RootMethod,
+    Signal, UserMapper, WaitStatus,
+};
 
 pub type Error = Box<dyn std::error::Error + Send + Sync>;
 
@@ -14,6 +31,17 @@ pub struct ContainerOptions {
     network_manager: Option<Arc<dyn NetworkManager>>,
     mounts: Vec<Arc<dyn Mount>>,
     hostname: String,
+    loopback: Option<bool>,
+    sysctls: Vec<(String, String)>,
+    controllers: Controllers,
+    time_namespace: Option<bool>,
+    root_method: RootMethod,
+    etc_hosts: bool,
+    kill_on_parent_death: bool,
+    join_namespaces: Option<(Pid, Namespaces)>,
+    init_binary: Option<PathBuf>,
+    privileged: bool,
+    group_source: GroupSource,
 }
 
 impl ContainerOptions {
@@ -21,6 +49,11 @@ impl ContainerOptions {
         Default::default()
     }
 
+    /// Sets path to container root filesystem.
+    ///
+    /// Path is bind mounted to itself before mounts are applied, so without
+    /// any `OverlayMount` a prepared directory is used as read-write rootfs
+    /// directly.
     pub fn rootfs(mut self, rootfs: PathBuf) -> Self {
         self.rootfs = Some(rootfs);
         self
@@ -46,22 +79,182 @@ impl ContainerOptions {
         self
     }
 
+    #[cfg(feature = "test-utils")]
+    pub(crate) fn add_boxed_mount(mut self, mount: Box<dyn Mount>) -> Self {
+        self.mounts.push(mount.into());
+        self
+    }
+
     pub fn hostname<T: ToString>(mut self, hostname: T) -> Self {
         self.hostname = hostname.to_string();
         self
     }
 
+    /// Enables or disables loopback interface setup in container network namespace.
+    ///
+    /// Loopback is brought up by default.
+    pub fn loopback(mut self, loopback: bool) -> Self {
+        self.loopback = Some(loopback);
+        self
+    }
+
+    /// Adds sysctl that is set by init process at container start.
+    ///
+    /// Key can be specified either in dotted (`net.ipv4.ip_forward`) or in path
+    /// (`net/ipv4/ip_forward`) form. Only namespaced sysctls (like `net.*` or
+    /// `kernel.shm*`) can be set from container user namespace.
+    pub fn sysctl<K: ToString, V: ToString>(mut self, key: K, value: V) -> Self {
+        self.sysctls.push((key.to_string(), value.to_string()));
+        self
+    }
+
+    /// Sets cgroup controllers enabled for subtree of container cgroup.
+    ///
+    /// Controllers are enabled on create, so limits of process cgroups can be
+    /// configured. Note that processes, including init process, should be
+    /// started in child cgroups when controllers are enabled.
+    pub fn controllers(mut self, controllers: Vec<String>) -> Self {
+        self.controllers = controllers.into();
+        self
+    }
+
+    /// Enables or disables time namespace for container.
+    ///
+    /// By default time namespace is used when it is supported by kernel.
+    pub fn time_namespace(mut self, time_namespace: bool) -> Self {
+        self.time_namespace = Some(time_namespace);
+        self
+    }
+
+    /// Sets method of changing root directory of container.
+    ///
+    /// Default is `RootMethod::PivotRoot`. `RootMethod::Chroot` is less secure
+    /// and should be used only where `pivot_root` is unavailable.
+    pub fn root_method(mut self, root_method: RootMethod) -> Self {
+        self.root_method = root_method;
+        self
+    }
+
+    /// Enables generation of `/etc/hostname` and `/etc/hosts` in container.
+    ///
+    /// Files with configured hostname are bind mounted over rootfs files, so
+    /// read-only rootfs or overlay lower layers are not modified.
+    pub fn etc_hosts(mut self, etc_hosts: bool) -> Self {
+        self.etc_hosts = etc_hosts;
+        self
+    }
+
+    /// Kills container init with `SIGKILL` when current thread exits.
+    ///
+    /// Uses `PR_SET_PDEATHSIG`, so whole container is torn down if managing
+    /// process dies unexpectedly. Note that signal is sent when thread that
+    /// started init exits, not whole process, so container should be started
+    /// from long-living thread.
+    pub fn kill_on_parent_death(mut self, kill_on_parent_death: bool) -> Self {
+        self.kill_on_parent_death = kill_on_parent_death;
+        self
+    }
+
+    /// Joins namespaces of specified init process instead of creating new ones.
+    ///
+    /// Kernel allows joining PID, network, IPC and UTS namespaces only with
+    /// privileges in user namespace that owns them, so user namespace of init
+    /// is joined too and user mapper is used only for user checks. Mount and
+    /// cgroup namespaces are always created. Hostname, loopback and network
+    /// are not configured for joined namespaces.
+    pub fn join_namespaces_of(mut self, init_pid: Pid, namespaces: Namespaces) -> Self {
+        self.join_namespaces = Some((init_pid, namespaces));
+        self
+    }
+
+    /// Runs specified init binary as init process with command as arguments.
+    ///
+    /// Init process executes `<init_binary> -- <command...>`, so init like
+    /// `tini` or `catatonit` forwards signals to command and reaps zombies.
+    /// Path is resolved inside container rootfs.
+    pub fn init_binary(mut self, init_binary: impl Into<PathBuf>) -> Self {
+        self.init_binary = Some(init_binary.into());
+        self
+    }
+
+    /// Runs container without user namespace as host root.
+    ///
+    /// Requires current process to run as root. User mapper is not used, so
+    /// any user and group can be specified for processes and they are
+    /// represented by same IDs on host.
+    pub fn privileged(mut self, privileged: bool) -> Self {
+        self.privileged = privileged;
+        self
+    }
+
+    /// Sets source of supplementary groups of processes in privileged container.
+    ///
+    /// Containers with user namespace use group source of user mapper.
+    pub fn group_source(mut self, group_source: GroupSource) -> Self {
+        self.group_source = group_source;
+        self
+    }
+
     pub fn create(self) -> Result<Container, Error> {
+        #[cfg(feature = "tracing")]
+        let _span = tracing::debug_span!("create_container", rootfs = ?self.rootfs).entered();
         let rootfs = self.rootfs.ok_or("Container rootfs should specified")?;
         let cgroup = self.cgroup.ok_or("Container cgroup should specified")?;
-        let user_mapper = self
-            .user_mapper
-            .ok_or("Container user mapper should specified")?;
+        let user_mapper = if self.privileged {
+            if self.user_mapper.is_some() {
+                return Err("Privileged container cannot use user mapper".into());
+            }
+            if !Uid::effective().is_root() {
+                return Err("Privileged container requires root".into());
+            }
+            Arc::new(HostUserMapper {
+                group_source: self.group_source,
+            })
+        } else {
+            check_user_namespaces()?;
+            self.user_mapper
+                .ok_or("Container user mapper should specified")?
+        };
         let network_manager = self.network_manager;
         let mounts = self.mounts;
         let hostname = self.hostname;
+        let loopback = self.loopback.unwrap_or(true);
+        let sysctls = self.sysctls;
+        let time_namespace = self
+            .time_namespace
+            .unwrap_or_else(|| Path::new("/proc/self/ns/time").exists());
+        // Directories created here are removed if creation fails.
+        let created_rootfs = rootfs.ancestors().take_while(|v| !v.exists()).last();
+        let created_rootfs = created_rootfs.map(Path::to_owned);
         create_dir_all(&rootfs)?;
-        cgroup.create()?;
+        let created_cgroup = !cgroup.as_path().exists();
+        let result = (|| -> Result<(), Error> {
+            cgroup.create()?;
+            if !self.controllers.is_empty() {
+                let available = cgroup.controller_set()?;
+                let missing = self.controllers.difference(&available);
+                if !missing.is_empty() {
+                    return Err(
+                        format!("Cgroup controllers are not available: \"{missing}\"").into(),
+                    );
+                }
+                cgroup
+                    .add_subtree_controllers(self.controllers.into())
+                    .map_err(|v| format!("Cannot enable cgroup controllers: {v}"))?;
+            }
+            Ok(())
+        })();
+        if let Err(err) = result {
+            if created_cgroup {
+                let _ = cgroup.remove();
+            }
+            if let Some(path) = created_rootfs {
+                let _ = remove_dir_all(path);
+            }
+            return Err(err);
+        }
+        #[cfg(feature = "tracing")]
+        tracing::debug!(cgroup = ?cgroup.as_path(), "container created");
         Ok(Container {
             rootfs,
             cgroup,
@@ -69,10 +262,164 @@ impl ContainerOptions {
             network_manager,
             mounts,
             hostname,
+            loopback,
+            sysctls,
+            time_namespace,
+            root_method: self.root_method,
+            etc_hosts: self.etc_hosts,
+            kill_on_parent_death: self.kill_on_parent_death,
+            join_namespaces: self.join_namespaces,
+            init_binary: self.init_binary,
+            privileged: self.privileged,
+            event_senders: Default::default(),
+            init_process: None,
         })
     }
 }
 
+/// Runs CRIU action with images in specified directory.
+///
+/// Error contains error lines from CRIU log on failure.
+fn run_criu(action: &str, dir: &Path, args: &[&OsStr]) -> Result<(), Error> {
+    let log_file = format!("{action}.log");
+    let status = std::process::Command::new("criu")
+        .arg(action)
+        .arg("--images-dir")
+        .arg(dir)
+        .arg("--log-file")
+        .arg(&log_file)
+        .args(args)
+        .stdin(std::process::Stdio::null())
+        .status()
+        .map_err(|v| match v.kind() {
+            std::io::ErrorKind::NotFound => "criu not found in PATH".to_owned(),
+            _ => format!("Cannot run criu: {v}"),
+        })?;
+    if status.success() {
+        return Ok(());
+    }
+    let log = std::fs::read_to_string(dir.join(&log_file)).unwrap_or_default();
+    let errors: Vec<_> = log.lines().filter(|v| v.contains("Error")).collect();
+    Err(format!("criu {action} failed with {status}: {}", errors.join("; ")).into())
+}
+
+/// Waits for exit of specified children of current process.
+fn reap_processes(pids: Vec<Pid>) -> Result<(), Error> {
+    for pid in pids {
+        match waitpid(pid, Some(WaitPidFlag::__WALL)) {
+            Ok(_) | Err(Errno::ECHILD) => {}
+            Err(v) => return Err(format!("Cannot reap process {pid}: {v}").into()),
+        }
+    }
+    Ok(())
+}
+
+/// Represents set of namespaces that can be joined by container.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Namespaces(u32);
+
+impl Namespaces {
+    pub const PID: Self = Self(1 << 0);
+    pub const NET: Self = Self(1 << 1);
+    pub const IPC: Self = Self(1 << 2);
+    pub const UTS: Self = Self(1 << 3);
+
+    pub fn empty() -> Self {
+        Self(0)
+    }
+
+    pub fn contains(&self, other: Self) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    pub(crate) fn clone_flags(&self) -> CloneFlags {
+        let mut flags = CloneFlags::empty();
+        for (namespace, flag) in [
+            (Self::PID, CloneFlags::CLONE_NEWPID),
+            (Self::NET, CloneFlags::CLONE_NEWNET),
+            (Self::IPC, CloneFlags::CLONE_NEWIPC),
+            (Self::UTS, CloneFlags::CLONE_NEWUTS),
+        ] {
+            if self.contains(namespace) {
+                flags |= flag;
+            }
+        }
+        flags
+    }
+}
+
+impl BitOr for Namespaces {
+    type Output = Self;
+
+    fn bitor(self, rhs: Self) -> Self {
+        Self(self.0 | rhs.0)
+    }
+}
+
+/// Represents inode numbers of namespaces of process.
+///
+/// Processes share namespace if and only if inode numbers are equal.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct NamespaceIds {
+    pub user: u64,
+    pub mnt: u64,
+    pub pid: u64,
+    pub net: u64,
+    pub ipc: u64,
+    pub uts: u64,
+    /// Time namespace requires Linux 5.6+.
+    pub time: Option<u64>,
+    pub cgroup: u64,
+}
+
+impl NamespaceIds {
+    /// Reads namespace ids of process from `/proc/<pid>/ns`.
+    pub fn read(pid: Pid) -> Result<Self, Error> {
+        let read = |name: &str| -> Result<u64, Error> {
+            let path = format!("/proc/{pid}/ns/{name}");
+            let metadata = std::fs::metadata(&path)
+                .map_err(|v| format!("Cannot read namespace {path:?}: {v}"))?;
+            Ok(metadata.ino())
+        };
+        let time = if Path::new(&format!("/proc/{pid}/ns/time")).exists() {
+            Some(read("time")?)
+        } else {
+            None
+        };
+        Ok(Self {
+            user: read("user")?,
+            mnt: read("mnt")?,
+            pid: read("pid")?,
+            net: read("net")?,
+            ipc: read("ipc")?,
+            uts: read("uts")?,
+            time,
+            cgroup: read("cgroup")?,
+        })
+    }
+}
+
+/// Represents lifecycle event of container.
+#[derive(Clone, Debug)]
+pub struct ContainerEvent {
+    pub time: SystemTime,
+    pub kind: ContainerEventKind,
+}
+
+#[derive(Clone, Debug)]
+pub enum ContainerEventKind {
+    /// Init process is started.
+    Started { pid: Pid },
+    /// Process is started in container namespaces.
+    ProcessSpawned { pid: Pid },
+    /// Processes of container cgroup were killed by OOM killer.
+    ///
+    /// Reported before `Stopped` with total number of kills.
+    OomKilled { count: usize },
+    /// Init process exited.
+    Stopped { status: WaitStatus },
+}
+
 pub struct Container {
     pub(super) rootfs: PathBuf,
     pub(super) cgroup: Cgroup,
@@ -80,10 +427,466 @@ pub struct Container {
     pub(super) network_manager: Option<Arc<dyn NetworkManager>>,
     pub(super) mounts: Vec<Arc<dyn Mount>>,
     pub(super) hostname: String,
+    pub(super) loopback: bool,
+    pub(super) sysctls: Vec<(String, String)>,
+    pub(super) time_namespace: bool,
+    pub(super) root_method: RootMethod,
+    pub(super) etc_hosts: bool,
+    pub(super) kill_on_parent_death: bool,
+    pub(super) join_namespaces: Option<(Pid, Namespaces)>,
+    pub(super) init_binary: Option<PathBuf>,
+    pub(super) privileged: bool,
+    event_senders: Arc<Mutex<Vec<Sender<ContainerEvent>>>>,
+    init_process: Option<InitProcess>,
 }
 
 impl Container {
     pub fn options() -> ContainerOptions {
         ContainerOptions::new()
     }
+
+    /// Returns namespaces joined instead of created.
+    pub(crate) fn joined_namespaces(&self) -> Namespaces {
+        self.join_namespaces
+            .map_or(Namespaces::empty(), |(_, namespaces)| namespaces)
+    }
+
+    /// Copies container configuration without init process.
+    pub(crate) fn clone_config(&self) -> Self {
+        Self {
+            rootfs: self.rootfs.clone(),
+            cgroup: self.cgroup.clone(),
+            user_mapper: self.user_mapper.clone(),
+            network_manager: self.network_manager.clone(),
+            mounts: self.mounts.clone(),
+            hostname: self.hostname.clone(),
+            loopback: self.loopback,
+            sysctls: self.sysctls.clone(),
+            time_namespace: self.time_namespace,
+            root_method: self.root_method,
+            etc_hosts: self.etc_hosts,
+            kill_on_parent_death: self.kill_on_parent_death,
+            join_namespaces: self.join_namespaces,
+            init_binary: self.init_binary.clone(),
+            privileged: self.privileged,
+            event_senders: self.event_senders.clone(),
+            init_process: None,
+        }
+    }
+
+    /// Starts container init process.
+    pub fn start(&mut self, options: InitProcessOptions) -> Result<&InitProcess, Error> {
+        if self.init_process.is_some() {
+            return Err("Container is already started".into());
+        }
+        let init_process = options.start(self)?;
+        self.emit(ContainerEventKind::Started {
+            pid: init_process.as_pid(),
+        });
+        Ok(self.init_process.insert(init_process))
+    }
+
+    /// Runs command as init process of container and waits for its exit.
+    ///
+    /// This is one-shot mode for running single program in sandbox without
+    /// separate init. Command runs as PID 1, so kernel does not apply default
+    /// action of signals without handlers to it, only `SIGKILL` terminates it
+    /// unconditionally. All remaining processes are killed after its exit.
+    pub fn run_single(&mut self, options: InitProcessOptions) -> Result<WaitStatus, Error> {
+        self.start(options)?;
+        let init_process = self.init_process.take().unwrap();
+        self.finish(init_process.as_pid())
+    }
+
+    /// Returns init process of started container.
+    pub fn init_process(&self) -> Option<&InitProcess> {
+        self.init_process.as_ref()
+    }
+
+    /// Returns pid of init process of started container.
+    ///
+    /// Pid is in PID namespace of current process, so `/proc/<pid>` can be
+    /// used directly to inspect container init.
+    pub fn init_pid(&self) -> Option<Pid> {
+        self.init_process.as_ref().map(|v| v.as_pid())
+    }
+
+    /// Returns true if init process of container is started and not exited.
+    ///
+    /// Exited init is not reaped, so its status can be read later.
+    pub fn is_running(&self) -> bool {
+        let pid = match self.init_pid() {
+            Some(v) => v,
+            None => return false,
+        };
+        let mut info: nix::libc::siginfo_t = unsafe { std::mem::zeroed() };
+        let result = Errno::result(unsafe {
+            nix::libc::waitid(
+                nix::libc::P_PID,
+                pid.as_raw() as nix::libc::id_t,
+                &mut info,
+                nix::libc::WEXITED | nix::libc::WNOHANG | nix::libc::WNOWAIT | nix::libc::__WALL,
+            )
+        });
+        // Field si_pid is zero if process has not changed state.
+        result.is_ok() && unsafe { info.si_pid() } == 0
+    }
+
+    /// Sets window size of console terminal of init process.
+    pub fn resize_pty(&self, rows: u16, cols: u16) -> Result<(), Error> {
+        self.init_process()
+            .ok_or("Container is not started")?
+            .resize_pty(rows, cols)
+    }
+
+    /// Returns mutable init process of started container.
+    pub fn init_process_mut(&mut self) -> Option<&mut InitProcess> {
+        self.init_process.as_mut()
+    }
+
+    /// Stops container by killing init process and waiting for its exit.
+    ///
+    /// All other processes in container PID namespace are killed by kernel
+    /// after init exit. See `Container::stop_with_signal` for details.
+    pub fn stop(&mut self) -> Result<WaitStatus, Error> {
+        self.stop_with_signal(Signal::SIGKILL)
+    }
+
+    /// Stops container by sending specified signal to init process and waiting for its exit.
+    ///
+    /// Kernel does not apply default action of signals for PID 1 of namespace,
+    /// so signals like `SIGTERM` or `SIGINT` are ignored unless init installs
+    /// handler for them. In such case this method blocks until init exits by
+    /// itself, so use `SIGKILL` for init without handlers.
+    ///
+    /// Processes started with `Process::start` are children of current process,
+    /// since they are cloned with `CLONE_PARENT`. Exiting init waits until such
+    /// processes are reaped, so exited ones are reaped while init is exiting
+    /// and `Process::wait` can fail for them after stop. After init exit all
+    /// remaining processes of container cgroup are killed and reaped. If this
+    /// cleanup fails, error contains exit status of init.
+    pub fn stop_with_signal(&mut self, signal: Signal) -> Result<WaitStatus, Error> {
+        let init_process = self.init_process.take().ok_or("Container is not started")?;
+        let init_pid = init_process.as_pid();
+        match kill(init_pid, signal) {
+            Ok(()) | Err(Errno::ESRCH) => {}
+            Err(v) => {
+                self.init_process = Some(init_process);
+                return Err(format!("Cannot send {signal} to init process: {v}").into());
+            }
+        }
+        self.finish(init_pid)
+    }
+
+    /// Waits for exit of init process and kills remaining processes of
+    /// container cgroup.
+    fn finish(&mut self, init_pid: Pid) -> Result<WaitStatus, Error> {
+        let status = self.wait_init(init_pid)?;
+        self.emit_stopped(status);
+        // Processes outside of container PID namespace are not killed by kernel.
+        (|| -> Result<(), Error> {
+            let children = self.cgroup_children(init_pid)?;
+            self.cgroup.kill()?;
+            reap_processes(children)
+        })()
+        .map_err(|v| format!("Container stopped with {status:?}, but cleanup failed: {v}"))?;
+        Ok(status)
+    }
+
+    /// Waits for exit of init process reaping exited children of current
+    /// process from container cgroup.
+    fn wait_init(&self, init_pid: Pid) -> Result<WaitStatus, Error> {
+        let mut children = Vec::new();
+        loop {
+            match waitpid(init_pid, Some(WaitPidFlag::WNOHANG | WaitPidFlag::__WALL)) {
+                Ok(WaitStatus::StillAlive) | Err(Errno::EINTR) => {}
+                Ok(status) => return Ok(status),
+                Err(v) => return Err(format!("Cannot wait for init process: {v}").into()),
+            }
+            // Processes become zombies after init exit and disappear from cgroup.
+            for pid in self.cgroup_children(init_pid).unwrap_or_default() {
+                if !children.contains(&pid) {
+                    children.push(pid);
+                }
+            }
+            children.retain(|&pid| {
+                matches!(
+                    waitpid(pid, Some(WaitPidFlag::WNOHANG | WaitPidFlag::__WALL)),
+                    Ok(WaitStatus::StillAlive) | Err(Errno::EINTR)
+                )
+            });
+            std::thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    /// Returns processes of container cgroup that are children of current
+    /// process except init.
+    fn cgroup_children(&self, init_pid: Pid) -> Result<Vec<Pid>, Error> {
+        let this = Pid::this();
+        let mut children = Vec::new();
+        for pid in self.cgroup.processes_recursive()? {
+            if pid == init_pid {
+                continue;
+            }
+            let stat = match std::fs::read_to_string(format!("/proc/{pid}/stat")) {
+                Ok(v) => v,
+                Err(_) => continue,
+            };
+            // Process name can contain spaces, so fields are parsed after it.
+            let ppid = stat
+                .rsplit_once(')')
+                .and_then(|v| v.1.split_whitespace().nth(1))
+                .and_then(|v| v.parse().ok());
+            if ppid == Some(this.as_raw()) {
+                children.push(pid);
+            }
+        }
+        Ok(children)
+    }
+
+    /// Checks that owner of specified path is mapped to container.
+    ///
+    /// Path is usually rootfs image or overlay lowerdir. Files owned by
+    /// unmapped IDs appear as owned by `nobody` in container, which leads to
+    /// permission errors at runtime. Such files should be created inside
+    /// `run_as_root` or mounted with `BindMount::idmap`. Only path itself is
+    /// checked, not its contents.
+    pub fn check_ownership(&self, path: impl AsRef<Path>) -> Result<(), Error> {
+        if self.privileged {
+            return Ok(());
+        }
+        let path = path.as_ref().to_owned();
+        let overflow_uid: u32 = std::fs::read_to_string("/proc/sys/kernel/overflowuid")?
+            .trim()
+            .parse()?;
+        let overflow_gid: u32 = std::fs::read_to_string("/proc/sys/kernel/overflowgid")?
+            .trim()
+            .parse()?;
+        run_as_root(&*self.user_mapper, move || {
+            let metadata = std::fs::metadata(&path)?;
+            if metadata.uid() == overflow_uid {
+                return Err(format!(
+                    "Owner of {path:?} is not mapped to container: chown it inside run_as_root or use idmapped mounts"
+                )
+                .into());
+            }
+            if metadata.gid() == overflow_gid {
+                return Err(format!(
+                    "Group of {path:?} is not mapped to container: chown it inside run_as_root or use idmapped mounts"
+                )
+                .into());
+            }
+            Ok(())
+        })
+    }
+
+    /// Checkpoints container processes to directory using CRIU and stops them.
+    ///
+    /// Cgroup of container is frozen during dump, so processes cannot change
+    /// state. This is experimental and supports only simple single-process
+    /// containers without network manager and console. Requires `criu` 3.15+
+    /// in `PATH` and `CAP_SYS_ADMIN` or `CAP_CHECKPOINT_RESTORE` (Linux 5.9+).
+    pub fn checkpoint(&mut self, dir: impl Into<PathBuf>) -> Result<WaitStatus, Error> {
+        let pid = self.init_pid().ok_or("Container is not started")?;
+        let dir = dir.into();
+        create_dir_all(&dir)?;
+        self.cgroup.freeze()?;
+        let result = run_criu(
+            "dump",
+            &dir,
+            &[
+                "--tree".as_ref(),
+                pid.to_string().as_ref(),
+                "--freeze-cgroup".as_ref(),
+                self.cgroup.as_path().as_os_str(),
+                "--manage-cgroups".as_ref(),
+            ],
+        );
+        // Processes are killed by CRIU after successful dump.
+        self.cgroup.thaw()?;
+        result?;
+        let mut init_process = self.init_process.take().unwrap();
+        let status = init_process.wait()?;
+        self.emit_stopped(status);
+        Ok(status)
+    }
+
+    /// Restores container processes from checkpoint created by `Container::checkpoint`.
+    ///
+    /// Container rootfs should be prepared at same path as during checkpoint.
+    /// Restored init process is child of current process.
+    pub fn restore(&mut self, dir: impl Into<PathBuf>) -> Result<&InitProcess, Error> {
+        if self.init_process.is_some() {
+            return Err("Container is already started".into());
+        }
+        let dir = dir.into();
+        let pidfile = dir.join("restore.pid");
+        match std::fs::remove_file(&pidfile) {
+            Ok(()) => {}
+            Err(v) if v.kind() == std::io::ErrorKind::NotFound => {}
+            Err(v) => return Err(v.into()),
+        }
+        run_criu(
+            "restore",
+            &dir,
+            &[
+                "--restore-detached".as_ref(),
+                "--restore-sibling".as_ref(),
+                "--pidfile".as_ref(),
+                pidfile.as_os_str(),
+                "--root".as_ref(),
+                self.rootfs.as_os_str(),
+                "--manage-cgroups".as_ref(),
+            ],
+        )?;
+        let pid = std::fs::read_to_string(&pidfile)?.trim().parse()?;
+        let init_process = InitProcess::restored(Pid::from_raw(pid));
+        self.emit(ContainerEventKind::Started {
+            pid: init_process.as_pid(),
+        });
+        Ok(self.init_process.insert(init_process))
+    }
+
+    /// Returns receiver of container lifecycle events.
+    ///
+    /// Each call creates new receiver that gets events emitted after call.
+    /// Events are emitted by current process only, so processes waited from
+    /// other threads are not reported.
+    pub fn events(&self) -> Receiver<ContainerEvent> {
+        let (tx, rx) = channel();
+        self.event_senders.lock().unwrap().push(tx);
+        rx
+    }
+
+    /// Sends event to all receivers dropping disconnected ones.
+    pub(crate) fn emit(&self, kind: ContainerEventKind) {
+        let event = ContainerEvent {
+            time: SystemTime::now(),
+            kind,
+        };
+        self.event_senders
+            .lock()
+            .unwrap()
+            .retain(|v| v.send(event.clone()).is_ok());
+    }
+
+    fn emit_stopped(&self, status: WaitStatus) {
+        if let Ok(events) = self.cgroup.memory_events() {
+            if events.oom_kill > 0 {
+                self.emit(ContainerEventKind::OomKilled {
+                    count: events.oom_kill,
+                });
+            }
+        }
+        self.emit(ContainerEventKind::Stopped { status });
+    }
+
+    /// Reaps all exited children of current process without blocking.
+    ///
+    /// Processes started by `ProcessOptions::start` are created with
+    /// `CLONE_PARENT`, so they are children of current (host) process rather
+    /// than of container init. If `Process::wait` is never called, exited
+    /// processes stay zombies until current process reaps them.
+    ///
+    /// Note that this method reaps any child of current process, including
+    /// init processes and processes of other containers, so it should be
+    /// used only when children are not awaited otherwise.
+    pub fn reap_zombies(&self) -> Result<Vec<WaitStatus>, Error> {
+        let mut statuses = Vec::new();
+        loop {
+            match waitpid(None, Some(WaitPidFlag::WNOHANG | WaitPidFlag::__WALL)) {
+                Ok(WaitStatus::StillAlive) | Err(Errno::ECHILD) => break,
+                Ok(status) => statuses.push(status),
+                Err(Errno::EINTR) => continue,
+                Err(v) => return Err(format!("Cannot reap zombies: {v}").into()),
+            }
+        }
+        Ok(statuses)
+    }
+
+    /// Returns mounts of container mount namespace.
+    pub fn mounts_info(&self) -> Result<Vec<MountInfo>, Error> {
+        let init_pid = self
+            .init_process()
+            .ok_or("Container is not started")?
+            .as_pid();
+        MountInfo::read(format!("/proc/{init_pid}/mountinfo"))
+    }
+
+    /// Returns namespace ids of container init process.
+    pub fn namespace_ids(&self) -> Result<NamespaceIds, Error> {
+        let init_pid = self.init_pid().ok_or("Container is not started")?;
+        NamespaceIds::read(init_pid)
+    }
+
+    /// Runs function inside all namespaces of container init process.
+    ///
+    /// Function runs in forked process, so it cannot modify memory of
+    /// current process. Result of function is passed back to caller.
+    pub fn with_namespaces<F>(&self, func: F) -> Result<(), Error>
+    where
+        F: FnOnce() -> Result<(), Error> + UnwindSafe,
+    {
+        let init_pid = self
+            .init_process()
+            .ok_or("Container is not started")?
+            .as_pid();
+        let time_namespace = self.time_namespace;
+        let privileged = self.privileged;
+        let pipe = new_pipe()?;
+        match unsafe { fork() }? {
+            ForkResult::Child => {
+                let _ = catch_unwind(move || {
+                    let tx = pipe.tx();
+                    set_child_panic_hook(Some(&tx));
+                    exit_child(move || -> Result<(), Error> {
+                        let pidfd = match pidfd_open(init_pid) {
+                            Ok(v) => v,
+                            Err(v) => return write_result(tx, Err(v.into()))?,
+                        };
+                        let mut flags = CloneFlags::CLONE_NEWUSER
+                            | CloneFlags::CLONE_NEWNS
+                            | CloneFlags::CLONE_NEWPID
+                            | CloneFlags::CLONE_NEWNET
+                            | CloneFlags::CLONE_NEWIPC
+                            | CloneFlags::CLONE_NEWUTS
+                            | CloneFlags::CLONE_NEWCGROUP;
+                        if time_namespace {
+                            flags |= CloneFlags::from_bits_retain(nix::libc::CLONE_NEWTIME);
+                        }
+                        if privileged {
+                            flags.remove(CloneFlags::CLONE_NEWUSER);
+                        }
+                        if let Err(v) = nix::sched::setns(&pidfd, flags) {
+                            let err = format!("Cannot enter init namespaces: {v}").into();
+                            return write_result(tx, Err(err))?;
+                        }
+                        drop(pidfd);
+                        if let Err(v) = join_root(self, init_pid) {
+                            return write_result(tx, Err(v))?;
+                        }
+                        // Fork again to become member of PID namespace.
+                        match unsafe { fork() }? {
+                            ForkResult::Child => {
+                                let _ = catch_unwind(move || exit_child(write_result(tx, func())));
+                                unsafe { nix::libc::_exit(2) }
+                            }
+                            ForkResult::Parent { child } => {
+                                drop(tx);
+                                unsafe { OwnedPid::from_raw(child) }.wait_success()
+                            }
+                        }
+                    }())
+                });
+                unsafe { nix::libc::_exit(2) }
+            }
+            ForkResult::Parent { child } => {
+                let child = unsafe { OwnedPid::from_raw(child) };
+                let rx = pipe.rx();
+                // Await function result.
+                read_result(rx)??;
+                child.wait_success()
+            }
+        }
+    }
 }
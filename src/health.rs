@@ -0,0 +1,139 @@
+use std::sync::mpsc::{channel, Receiver, RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+use crate::{Container, Error, Pid, ProcessOptions, WaitStatus};
+
+/// Represents health status of container.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum HealthStatus {
+    /// No check has succeeded or exhausted retries yet.
+    Starting,
+    Healthy,
+    Unhealthy,
+}
+
+/// Represents transition of container health status.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct HealthEvent {
+    pub from: HealthStatus,
+    pub to: HealthStatus,
+}
+
+/// Represents health check running on background thread.
+///
+/// Checks are stopped when handle is dropped.
+pub struct HealthCheck {
+    status: Arc<Mutex<HealthStatus>>,
+    events: Receiver<HealthEvent>,
+    stop: Option<Sender<()>>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl HealthCheck {
+    /// Returns current health status.
+    pub fn status(&self) -> HealthStatus {
+        *self.status.lock().unwrap()
+    }
+
+    /// Returns receiver of health status transitions.
+    pub fn events(&self) -> &Receiver<HealthEvent> {
+        &self.events
+    }
+}
+
+impl Drop for HealthCheck {
+    fn drop(&mut self) {
+        drop(self.stop.take());
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+impl Container {
+    /// Starts periodic health check of running container.
+    ///
+    /// Every `interval` a process created by `options` is executed in container.
+    /// Check succeeds if process exits with zero code within `timeout`, otherwise
+    /// process is killed. Container becomes unhealthy after `retries` consecutive
+    /// failed checks and healthy after any successful check.
+    pub fn healthcheck<F>(
+        &self,
+        options: F,
+        interval: Duration,
+        timeout: Duration,
+        retries: u32,
+    ) -> Result<HealthCheck, Error>
+    where
+        F: Fn() -> ProcessOptions + Send + 'static,
+    {
+        let init_pid = self
+            .init_process()
+            .ok_or("Container is not started")?
+            .as_pid();
+        let container = self.clone_config();
+        let status = Arc::new(Mutex::new(HealthStatus::Starting));
+        let (event_tx, event_rx) = channel();
+        let (stop_tx, stop_rx) = channel();
+        let thread = {
+            let status = status.clone();
+            std::thread::spawn(move || {
+                let mut failures = 0;
+                loop {
+                    let current = *status.lock().unwrap();
+                    let next = if run_check(&container, init_pid, options(), timeout) {
+                        failures = 0;
+                        HealthStatus::Healthy
+                    } else {
+                        failures += 1;
+                        if failures >= retries.max(1) {
+                            HealthStatus::Unhealthy
+                        } else {
+                            current
+                        }
+                    };
+                    if next != current {
+                        *status.lock().unwrap() = next;
+                        let _ = event_tx.send(HealthEvent {
+                            from: current,
+                            to: next,
+                        });
+                    }
+                    match stop_rx.recv_timeout(interval) {
+                        Err(RecvTimeoutError::Timeout) => continue,
+                        _ => break,
+                    }
+                }
+            })
+        };
+        Ok(HealthCheck {
+            status,
+            events: event_rx,
+            stop: Some(stop_tx),
+            thread: Some(thread),
+        })
+    }
+}
+
+fn run_check(
+    container: &Container,
+    init_pid: Pid,
+    options: ProcessOptions,
+    timeout: Duration,
+) -> bool {
+    let mut process = match options.spawn(container, init_pid) {
+        Ok(v) => v,
+        Err(_) => return false,
+    };
+    match process.wait_timeout(timeout) {
+        Ok(Some(WaitStatus::Exited(_, 0))) => true,
+        Ok(Some(_)) | Err(_) => false,
+        Ok(None) => {
+            let _ = nix::sys::signal::kill(process.as_pid(), nix::sys::signal::Signal::SIGKILL);
+            let _ = process.wait();
+            false
+        }
+    }
+}
@@ -0,0 +1,236 @@
+use std::ops::{BitOr, BitOrAssign};
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+use std::path::{Path, PathBuf};
+
+use nix::errno::Errno;
+use nix::fcntl::OFlag;
+
+use crate::Error;
+
+const LANDLOCK_CREATE_RULESET_VERSION: u32 = 1;
+const LANDLOCK_RULE_PATH_BENEATH: u32 = 1;
+
+/// Represents set of filesystem access rights of landlock.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct AccessFs(u64);
+
+impl AccessFs {
+    pub const EXECUTE: Self = Self(1 << 0);
+    pub const WRITE_FILE: Self = Self(1 << 1);
+    pub const READ_FILE: Self = Self(1 << 2);
+    pub const READ_DIR: Self = Self(1 << 3);
+    pub const REMOVE_DIR: Self = Self(1 << 4);
+    pub const REMOVE_FILE: Self = Self(1 << 5);
+    pub const MAKE_CHAR: Self = Self(1 << 6);
+    pub const MAKE_DIR: Self = Self(1 << 7);
+    pub const MAKE_REG: Self = Self(1 << 8);
+    pub const MAKE_SOCK: Self = Self(1 << 9);
+    pub const MAKE_FIFO: Self = Self(1 << 10);
+    pub const MAKE_BLOCK: Self = Self(1 << 11);
+    pub const MAKE_SYM: Self = Self(1 << 12);
+    /// Requires landlock ABI 2 (Linux 5.19+).
+    pub const REFER: Self = Self(1 << 13);
+    /// Requires landlock ABI 3 (Linux 6.2+).
+    pub const TRUNCATE: Self = Self(1 << 14);
+
+    /// Rights for reading files and directories and executing files.
+    pub const READ: Self = Self(Self::EXECUTE.0 | Self::READ_FILE.0 | Self::READ_DIR.0);
+    /// All rights supported by this crate.
+    pub const ALL: Self = Self((1 << 15) - 1);
+
+    /// Rights that can be granted for regular files.
+    const FILE: Self =
+        Self(Self::EXECUTE.0 | Self::WRITE_FILE.0 | Self::READ_FILE.0 | Self::TRUNCATE.0);
+
+    pub fn empty() -> Self {
+        Self(0)
+    }
+
+    pub fn bits(&self) -> u64 {
+        self.0
+    }
+
+    pub fn contains(&self, other: Self) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0 == 0
+    }
+
+    fn intersection(self, other: Self) -> Self {
+        Self(self.0 & other.0)
+    }
+
+    /// Returns rights supported by specified landlock ABI version.
+    fn supported(abi: i32) -> Self {
+        match abi {
+            ..=0 => Self::empty(),
+            1 => Self((1 << 13) - 1),
+            2 => Self((1 << 14) - 1),
+            _ => Self::ALL,
+        }
+    }
+}
+
+impl BitOr for AccessFs {
+    type Output = Self;
+
+    fn bitor(self, rhs: Self) -> Self {
+        Self(self.0 | rhs.0)
+    }
+}
+
+impl BitOrAssign for AccessFs {
+    fn bitor_assign(&mut self, rhs: Self) {
+        self.0 |= rhs.0
+    }
+}
+
+/// Represents landlock ruleset restricting filesystem access of process.
+///
+/// Access rights listed as handled are denied everywhere except paths
+/// allowed by rules. Rights not handled are not restricted.
+#[derive(Clone, Debug)]
+pub struct LandlockRuleset {
+    handled: AccessFs,
+    rules: Vec<(PathBuf, AccessFs)>,
+    best_effort: bool,
+}
+
+impl LandlockRuleset {
+    /// Creates ruleset that handles all known filesystem access rights.
+    pub fn new() -> Self {
+        Self {
+            handled: AccessFs::ALL,
+            rules: Vec::new(),
+            best_effort: false,
+        }
+    }
+
+    /// Sets access rights handled by ruleset.
+    pub fn handle(mut self, access: AccessFs) -> Self {
+        self.handled = access;
+        self
+    }
+
+    /// Allows access rights beneath path inside container.
+    pub fn allow(mut self, path: impl Into<PathBuf>, access: AccessFs) -> Self {
+        self.rules.push((path.into(), access));
+        self
+    }
+
+    /// Merges handled rights and rules of other ruleset.
+    pub fn merge(mut self, other: LandlockRuleset) -> Self {
+        self.handled |= other.handled;
+        self.rules.extend(other.rules);
+        self.best_effort &= other.best_effort;
+        self
+    }
+
+    /// Ignores missing landlock support instead of failing.
+    ///
+    /// Landlock requires Linux 5.13+ with landlock enabled in LSM list. Rights
+    /// unsupported by kernel are dropped in both modes.
+    pub fn best_effort(mut self, best_effort: bool) -> Self {
+        self.best_effort = best_effort;
+        self
+    }
+
+    /// Enforces ruleset for current process.
+    ///
+    /// Sets `PR_SET_NO_NEW_PRIVS`, which is required for unprivileged processes.
+    pub(crate) fn restrict_self(&self) -> Result<(), Error> {
+        let abi = match landlock_abi() {
+            Ok(v) => v,
+            Err(Errno::ENOSYS | Errno::EOPNOTSUPP) if self.best_effort => return Ok(()),
+            Err(v) => return Err(format!("Landlock is not supported: {v}").into()),
+        };
+        let handled = self.handled.intersection(AccessFs::supported(abi));
+        let attr = handled.bits();
+        let ruleset = Errno::result(unsafe {
+            nix::libc::syscall(
+                nix::libc::SYS_landlock_create_ruleset,
+                &attr as *const u64,
+                std::mem::size_of::<u64>(),
+                0,
+            )
+        })
+        .map_err(|v| format!("Cannot create landlock ruleset: {v}"))?;
+        let ruleset = unsafe { OwnedFd::from_raw_fd(ruleset as i32) };
+        for (path, access) in &self.rules {
+            add_path_rule(&ruleset, path, access.intersection(handled))?;
+        }
+        Errno::result(unsafe { nix::libc::prctl(nix::libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) })
+            .map_err(|v| format!("Cannot set no_new_privs: {v}"))?;
+        Errno::result(unsafe {
+            nix::libc::syscall(
+                nix::libc::SYS_landlock_restrict_self,
+                ruleset.as_raw_fd(),
+                0,
+            )
+        })
+        .map_err(|v| format!("Cannot enforce landlock ruleset: {v}"))?;
+        Ok(())
+    }
+}
+
+impl Default for LandlockRuleset {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[repr(C, packed)]
+struct PathBeneathAttr {
+    allowed_access: u64,
+    parent_fd: i32,
+}
+
+fn add_path_rule(ruleset: &OwnedFd, path: &Path, access: AccessFs) -> Result<(), Error> {
+    // Effective rights are limited to handled rights supported by kernel and,
+    // for regular files, to file rights. Kernel rejects empty rules with
+    // ENOMSG, so such rules are skipped.
+    let access = if std::fs::metadata(path)?.is_dir() {
+        access
+    } else {
+        access.intersection(AccessFs::FILE)
+    };
+    if access.is_empty() {
+        return Ok(());
+    }
+    let fd = nix::fcntl::open(
+        path,
+        OFlag::O_PATH | OFlag::O_CLOEXEC,
+        nix::sys::stat::Mode::empty(),
+    )
+    .map_err(|v| format!("Cannot open landlock path {path:?}: {v}"))?;
+    let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+    let attr = PathBeneathAttr {
+        allowed_access: access.bits(),
+        parent_fd: fd.as_raw_fd(),
+    };
+    Errno::result(unsafe {
+        nix::libc::syscall(
+            nix::libc::SYS_landlock_add_rule,
+            ruleset.as_raw_fd(),
+            LANDLOCK_RULE_PATH_BENEATH,
+            &attr as *const PathBeneathAttr,
+            0,
+        )
+    })
+    .map_err(|v| format!("Cannot add landlock rule for {path:?}: {v}"))?;
+    Ok(())
+}
+
+fn landlock_abi() -> Result<i32, Errno> {
+    let res = unsafe {
+        nix::libc::syscall(
+            nix::libc::SYS_landlock_create_ruleset,
+            std::ptr::null::<u64>(),
+            0,
+            LANDLOCK_CREATE_RULESET_VERSION,
+        )
+    };
+    Errno::result(res).map(|v| v as i32)
+}
@@ -1,13 +1,19 @@
 mod cgroup;
 mod container;
+mod health;
+mod landlock;
 mod mount;
 mod network;
 mod process;
 mod syscall;
+#[cfg(feature = "test-utils")]
+pub mod test_utils;
 mod user;
 
 pub use cgroup::*;
 pub use container::*;
+pub use health::*;
+pub use landlock::*;
 pub use mount::*;
 pub use network::*;
 pub use process::*;
@@ -1,37 +1,146 @@
+use nix::errno::Errno;
 use nix::fcntl::{open, OFlag};
 use nix::mount::{mount, umount2, MntFlags, MsFlags};
-use nix::unistd::fchdir;
+use nix::sys::signal::{kill, Signal};
+use nix::sys::socket::{recvmsg, sendmsg, ControlMessage, ControlMessageOwned, MsgFlags};
+use nix::sys::statfs::{
+    statfs, CRAMFS_MAGIC, ISOFS_SUPER_MAGIC, MSDOS_SUPER_MAGIC, NFS_SUPER_MAGIC,
+    OVERLAYFS_SUPER_MAGIC, SMB_SUPER_MAGIC,
+};
+use nix::sys::statvfs::{statvfs, FsFlags};
+use nix::sys::wait::waitpid;
+use nix::unistd::{dup2, execvp, fchdir, fork, ForkResult, Pid};
+use std::ffi::{CString, OsString};
 use std::fmt::Debug;
-use std::fs::create_dir;
-use std::io::ErrorKind;
+use std::fs::{create_dir, create_dir_all, File};
+use std::io::{ErrorKind, IoSlice, IoSliceMut};
+use std::os::fd::{AsFd, AsRawFd, BorrowedFd, FromRawFd, OwnedFd, RawFd};
+use std::os::unix::ffi::OsStringExt;
+use std::os::unix::fs::{symlink, MetadataExt};
+use std::os::unix::net::UnixStream;
 use std::panic::RefUnwindSafe;
 use std::path::{Path, PathBuf};
 
-use crate::{ignore_kind, Container, Error};
+use crate::{
+    ignore_kind, mount_setattr, move_mount, open_tree, ChildError, Container, Error, MountAttr,
+    MOUNT_ATTR_IDMAP, MOUNT_ATTR_RDONLY,
+};
 
 pub trait Mount: Send + Sync + Debug + RefUnwindSafe {
     fn mount(&self, rootfs: &Path) -> Result<(), Error>;
+
+    /// Finishes mount after root of container is changed.
+    ///
+    /// Changing root makes all mounts slaves, so settings like propagation
+    /// should be applied here.
+    fn finalize(&self) -> Result<(), Error> {
+        Ok(())
+    }
 }
 
+/// Represents overlayfs mount for container rootfs.
+///
+/// Note that overlayfs treats the first lowerdir as the topmost layer. Docker
+/// and OCI images list layers in reverse order (base layer first), so use
+/// `OverlayMount::from_docker_order` for layers in such order.
 #[derive(Debug, Clone)]
 pub struct OverlayMount {
+    /// Lower layers, from topmost to bottommost.
     pub lowerdir: Vec<PathBuf>,
     pub upperdir: PathBuf,
     pub workdir: PathBuf,
+    /// Value of `index` option, kernel default is used if not set.
+    pub index: Option<bool>,
+    /// Value of `xino` option, kernel default is used if not set.
+    pub xino: Option<bool>,
+    /// Value of `metacopy` option, kernel default is used if not set.
+    pub metacopy: Option<bool>,
 }
 
 impl OverlayMount {
+    /// Creates overlay with lowerdir layers ordered from topmost to bottommost.
     pub fn new(lowerdir: Vec<PathBuf>, upperdir: PathBuf, workdir: PathBuf) -> Self {
         Self {
             lowerdir,
             upperdir,
             workdir,
+            index: None,
+            xino: None,
+            metacopy: None,
+        }
+    }
+
+    /// Sets `index` option, required for NFS export and hardlinks preserving
+    /// on copy up.
+    pub fn index(mut self, index: bool) -> Self {
+        self.index = Some(index);
+        self
+    }
+
+    /// Sets `xino` option, that makes inode numbers stable across layers.
+    pub fn xino(mut self, xino: bool) -> Self {
+        self.xino = Some(xino);
+        self
+    }
+
+    /// Sets `metacopy` option, that copies up only metadata on change.
+    ///
+    /// Cannot be enabled with disabled index.
+    pub fn metacopy(mut self, metacopy: bool) -> Self {
+        self.metacopy = Some(metacopy);
+        self
+    }
+
+    /// Creates overlay with lowerdir layers ordered from bottommost to topmost.
+    pub fn from_docker_order(
+        mut lowerdir: Vec<PathBuf>,
+        upperdir: PathBuf,
+        workdir: PathBuf,
+    ) -> Self {
+        lowerdir.reverse();
+        Self::new(lowerdir, upperdir, workdir)
+    }
+
+    /// Creates overlay with upperdir and workdir on anonymous tmpfs.
+    ///
+    /// Size of tmpfs is specified in bytes.
+    pub fn ephemeral(lowerdir: Vec<PathBuf>, size: usize) -> EphemeralOverlayMount {
+        EphemeralOverlayMount { lowerdir, size }
+    }
+
+    fn validate(&self) -> Result<(), Error> {
+        if self.lowerdir.is_empty() {
+            Err("Overlay lowerdir cannot be empty")?
+        }
+        if self.lowerdir.len() > OVERLAY_MAX_LOWERDIR {
+            Err(format!(
+                "Overlay lowerdir has {} layers, limit is {OVERLAY_MAX_LOWERDIR} layers",
+                self.lowerdir.len(),
+            ))?
+        }
+        for path in &self.lowerdir {
+            check_dir("lowerdir", path)?;
         }
+        if self.metacopy == Some(true) && self.index == Some(false) {
+            Err("Overlay metacopy=on cannot be used with index=off")?
+        }
+        check_dir("upperdir", &self.upperdir)?;
+        check_dir("workdir", &self.workdir)?;
+        check_upper_fs("upperdir", &self.upperdir)?;
+        check_upper_fs("workdir", &self.workdir)?;
+        if self.upperdir.metadata()?.dev() != self.workdir.metadata()?.dev() {
+            Err(format!(
+                "Overlay upperdir {:?} and workdir {:?} should be on the same filesystem",
+                self.upperdir, self.workdir,
+            ))?
+        }
+        Ok(())
     }
 }
 
 impl Mount for OverlayMount {
     fn mount(&self, rootfs: &Path) -> Result<(), Error> {
+        self.validate()?;
         let lowerdir =
             Option::<Vec<_>>::from_iter(self.lowerdir.iter().map(|v| v.as_os_str().to_str()))
                 .ok_or(format!("Invalid overlay lowerdir: {:?}", self.lowerdir))?
@@ -46,7 +155,26 @@ impl Mount for OverlayMount {
             .as_os_str()
             .to_str()
             .ok_or(format!("Invalid overlay workdir: {:?}", self.workdir))?;
-        let mount_data = format!("lowerdir={lowerdir},upperdir={upperdir},workdir={workdir}");
+        let mut mount_data = format!("lowerdir={lowerdir},upperdir={upperdir},workdir={workdir}");
+        for (name, value) in [
+            ("index", self.index),
+            ("xino", self.xino),
+            ("metacopy", self.metacopy),
+        ] {
+            if let Some(value) = value {
+                let value = if value { "on" } else { "off" };
+                mount_data.push_str(&format!(",{name}={value}"));
+            }
+        }
+        // Kernel copies at most one page of mount data including terminating zero.
+        let page_size = unsafe { nix::libc::sysconf(nix::libc::_SC_PAGESIZE) } as usize;
+        if mount_data.len() >= page_size {
+            Err(format!(
+                "Overlay mount options are {} bytes, limit is {} bytes: use fewer lowerdir layers or shorter paths",
+                mount_data.len(),
+                page_size - 1,
+            ))?
+        }
         Ok(mount(
             "overlay".into(),
             rootfs,
@@ -57,12 +185,482 @@ impl Mount for OverlayMount {
     }
 }
 
+/// Maximum amount of overlay lower layers supported by kernel.
+const OVERLAY_MAX_LOWERDIR: usize = 500;
+
+/// Represents overlayfs mount with writable layer on anonymous tmpfs.
+///
+/// Tmpfs is mounted only in container mount namespace, so all changes are
+/// released by kernel when container exits, even if caller panics.
 #[derive(Debug, Clone)]
-pub struct BaseMounts {}
+pub struct EphemeralOverlayMount {
+    /// Lower layers, from topmost to bottommost.
+    pub lowerdir: Vec<PathBuf>,
+    /// Size of tmpfs in bytes.
+    pub size: usize,
+}
+
+impl Mount for EphemeralOverlayMount {
+    fn mount(&self, rootfs: &Path) -> Result<(), Error> {
+        // Tmpfs is mounted on rootfs and then covered by overlay.
+        mount(
+            "tmpfs".into(),
+            rootfs,
+            "tmpfs".into(),
+            MsFlags::MS_NOSUID | MsFlags::MS_NODEV,
+            Some(format!("mode=755,size={}", self.size).as_str()),
+        )?;
+        let upperdir = rootfs.join("upper");
+        let workdir = rootfs.join("work");
+        create_dir(&upperdir)?;
+        create_dir(&workdir)?;
+        OverlayMount::new(self.lowerdir.clone(), upperdir, workdir).mount(rootfs)
+    }
+}
+
+/// Represents read-only bind mount of rootfs with writable tmpfs directories.
+///
+/// Alternative to overlayfs for hosts where unprivileged overlayfs is not
+/// available. Unlike overlayfs this is not copy-on-write: writable directories
+/// start empty and files of rootfs under them are hidden, all other paths
+/// stay read-only. Tmpfs is released by kernel when container exits.
+#[derive(Debug, Clone)]
+pub struct LayeredTmpfsMount {
+    /// Path of rootfs on host.
+    pub source: PathBuf,
+    /// Directories inside container that are covered by tmpfs.
+    pub writable: Vec<PathBuf>,
+    /// Size of each tmpfs in bytes.
+    pub size: usize,
+}
+
+impl LayeredTmpfsMount {
+    pub fn new(source: impl Into<PathBuf>, writable: Vec<PathBuf>, size: usize) -> Self {
+        Self {
+            source: source.into(),
+            writable,
+            size,
+        }
+    }
+}
+
+impl Mount for LayeredTmpfsMount {
+    fn mount(&self, rootfs: &Path) -> Result<(), Error> {
+        mount(
+            Some(&self.source),
+            rootfs,
+            None::<&str>,
+            MsFlags::MS_BIND | MsFlags::MS_REC,
+            None::<&str>,
+        )?;
+        remount_readonly_recursive(rootfs)?;
+        for path in &self.writable {
+            let target = rootfs.join(path.strip_prefix("/").unwrap_or(path));
+            // Rootfs is read-only, so directory should exist in it.
+            let metadata = std::fs::metadata(&target)
+                .map_err(|v| format!("Invalid writable directory {path:?}: {v}"))?;
+            if !metadata.is_dir() {
+                Err(format!("Writable path is not a directory: {path:?}"))?
+            }
+            // Mode is preserved, so sticky bit of /tmp is kept.
+            let mode = metadata.mode() & 0o7777;
+            mount(
+                "tmpfs".into(),
+                &target,
+                "tmpfs".into(),
+                MsFlags::MS_NOSUID | MsFlags::MS_NODEV,
+                Some(format!("mode={mode:o},size={}", self.size).as_str()),
+            )
+            .map_err(|v| format!("Cannot mount tmpfs at {path:?}: {v}"))?;
+        }
+        Ok(())
+    }
+}
+
+/// Represents bind mount of host path into container.
+#[derive(Debug, Clone)]
+pub struct BindMount {
+    /// Path on host.
+    pub source: PathBuf,
+    /// Path inside container.
+    pub target: PathBuf,
+    pub readonly: bool,
+    /// Creates idmapped mount using container user namespace.
+    pub idmap: bool,
+    /// Propagation type of mount, inherited from parent mount if not set.
+    pub propagation: Option<Propagation>,
+}
+
+impl BindMount {
+    pub fn new(source: impl Into<PathBuf>, target: impl Into<PathBuf>) -> Self {
+        Self {
+            source: source.into(),
+            target: target.into(),
+            readonly: false,
+            idmap: false,
+            propagation: None,
+        }
+    }
+
+    /// Makes mount and all its submounts read-only.
+    ///
+    /// Uses recursive `mount_setattr` on Linux 5.12+, on older kernels
+    /// submounts are remounted one by one.
+    pub fn readonly(mut self, readonly: bool) -> Self {
+        self.readonly = readonly;
+        self
+    }
+
+    /// Enables idmapped mount (requires Linux 5.12+).
+    ///
+    /// File owners on source filesystem are treated as container IDs, so files
+    /// owned by host root appear as owned by container root without chown.
+    /// Creating idmapped mount requires privileges over the source mount, which
+    /// usually means that container should be created by host root.
+    pub fn idmap(mut self, idmap: bool) -> Self {
+        self.idmap = idmap;
+        self
+    }
+
+    /// Sets propagation type of mount and its submounts.
+    ///
+    /// Container root is made slave of host, so by default host changes are
+    /// propagated to bind mounts of shared host mounts, but not vice versa.
+    pub fn propagation(mut self, propagation: Propagation) -> Self {
+        self.propagation = Some(propagation);
+        self
+    }
+
+    fn mount_idmapped(&self, target: &Path) -> Result<(), Error> {
+        let tree = open_tree(
+            &self.source,
+            nix::libc::OPEN_TREE_CLONE | nix::libc::OPEN_TREE_CLOEXEC,
+        )
+        .map_err(idmap_error)?;
+        let userns = File::open("/proc/self/ns/user")?;
+        let mut attr = MountAttr {
+            attr_set: MOUNT_ATTR_IDMAP,
+            userns_fd: userns.as_raw_fd() as u64,
+            ..Default::default()
+        };
+        if self.readonly {
+            attr.attr_set |= MOUNT_ATTR_RDONLY;
+        }
+        mount_setattr(&tree, 0, &attr).map_err(idmap_error)?;
+        Ok(move_mount(&tree, target)?)
+    }
+}
+
+impl Mount for BindMount {
+    fn mount(&self, rootfs: &Path) -> Result<(), Error> {
+        let target = rootfs.join(self.target.strip_prefix("/").unwrap_or(&self.target));
+        if std::fs::metadata(&self.source)?.is_dir() {
+            create_dir_all(&target)?;
+        } else if !target.exists() {
+            if let Some(parent) = target.parent() {
+                create_dir_all(parent)?;
+            }
+            File::create(&target)?;
+        }
+        if self.idmap {
+            self.mount_idmapped(&target)?;
+        } else {
+            mount(
+                Some(&self.source),
+                &target,
+                None::<&str>,
+                MsFlags::MS_BIND | MsFlags::MS_REC,
+                None::<&str>,
+            )?;
+            if self.readonly {
+                remount_readonly_recursive(&target)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn finalize(&self) -> Result<(), Error> {
+        if let Some(propagation) = self.propagation {
+            mount(
+                None::<&str>,
+                &self.target,
+                None::<&str>,
+                propagation.flags() | MsFlags::MS_REC,
+                None::<&str>,
+            )
+            .map_err(|v| format!("Cannot set {propagation:?} propagation: {v}"))?;
+        }
+        Ok(())
+    }
+}
+
+/// Represents propagation type of mount.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Propagation {
+    /// Mount events are not propagated in any direction.
+    Private,
+    /// Mount events are propagated between peer mounts in both directions.
+    Shared,
+    /// Mount events are received from master mount only.
+    Slave,
+    /// Private mount that cannot be bind mounted.
+    Unbindable,
+}
+
+impl Propagation {
+    fn flags(self) -> MsFlags {
+        match self {
+            Self::Private => MsFlags::MS_PRIVATE,
+            Self::Shared => MsFlags::MS_SHARED,
+            Self::Slave => MsFlags::MS_SLAVE,
+            Self::Unbindable => MsFlags::MS_UNBINDABLE,
+        }
+    }
+}
+
+/// Represents FUSE filesystem mount served by helper process on host.
+///
+/// Helper is started on host and waits until container opens `/dev/fuse`
+/// and sends device descriptor over unix socket. Then helper is executed
+/// with device passed as last argument in form of `/dev/fd/N` (supported by
+/// libfuse 3.3+). Kernel requires device to be opened in user namespace of
+/// mount, so device cannot be opened on host for unprivileged container.
+///
+/// Mounting FUSE in user namespace requires Linux 4.18+ and read-write access
+/// to `/dev/fuse` for container root. Files are owned by container root.
+/// Helper serves single mount, so container with it can be started once.
+#[derive(Debug)]
+pub struct FuseMount {
+    target: PathBuf,
+    socket: UnixStream,
+    helper: Pid,
+}
+
+impl FuseMount {
+    /// Starts FUSE helper for mount at target.
+    pub fn new(target: impl Into<PathBuf>, command: Vec<String>) -> Result<Self, Error> {
+        let program = command.first().ok_or("FUSE helper command is empty")?;
+        let (socket, helper_socket) = UnixStream::pair()?;
+        // Received device replaces socket, so its number is known before fork.
+        let raw_fd = helper_socket.as_raw_fd();
+        let mut args = Vec::new();
+        for arg in &command {
+            args.push(CString::new(arg.as_str())?);
+        }
+        args.push(CString::new(format!("/dev/fd/{raw_fd}"))?);
+        let program = CString::new(program.as_str())?;
+        match unsafe { fork() }? {
+            ForkResult::Child => {
+                drop(socket);
+                if let Ok(device) = recv_fd(&helper_socket) {
+                    if dup2(device.as_raw_fd(), raw_fd).is_ok() {
+                        drop(device);
+                        let _ = execvp(&program, &args);
+                    }
+                }
+                unsafe { nix::libc::_exit(127) }
+            }
+            ForkResult::Parent { child } => Ok(Self {
+                target: target.into(),
+                socket,
+                helper: child,
+            }),
+        }
+    }
+}
+
+impl Mount for FuseMount {
+    fn mount(&self, rootfs: &Path) -> Result<(), Error> {
+        let target = rootfs.join(self.target.strip_prefix("/").unwrap_or(&self.target));
+        create_dir_all(&target)?;
+        let device = File::options()
+            .read(true)
+            .write(true)
+            .open("/dev/fuse")
+            .map_err(|v| format!("Cannot open /dev/fuse: {v}"))?;
+        let data = format!(
+            "fd={},rootmode=40000,user_id=0,group_id=0",
+            device.as_raw_fd()
+        );
+        mount(
+            "fuse".into(),
+            &target,
+            "fuse".into(),
+            MsFlags::MS_NOSUID | MsFlags::MS_NODEV,
+            Some(data.as_str()),
+        )?;
+        send_fd(&self.socket, device.as_fd())
+            .map_err(|v| format!("Cannot pass /dev/fuse to FUSE helper: {v}"))?;
+        Ok(())
+    }
+}
+
+impl Drop for FuseMount {
+    fn drop(&mut self) {
+        let _ = kill(self.helper, Signal::SIGKILL);
+        while let Err(Errno::EINTR) = waitpid(self.helper, None) {}
+    }
+}
+
+/// Sends file descriptor over unix socket.
+fn send_fd(socket: &UnixStream, fd: BorrowedFd) -> Result<(), Errno> {
+    let fds = [fd.as_raw_fd()];
+    let cmsg = [ControlMessage::ScmRights(&fds)];
+    sendmsg::<()>(
+        socket.as_raw_fd(),
+        &[IoSlice::new(&[0])],
+        &cmsg,
+        MsgFlags::empty(),
+        None,
+    )?;
+    Ok(())
+}
+
+/// Receives file descriptor sent with `send_fd`.
+fn recv_fd(socket: &UnixStream) -> Result<OwnedFd, Errno> {
+    let mut buf = [0; 1];
+    let mut iov = [IoSliceMut::new(&mut buf)];
+    let mut cmsg = nix::cmsg_space!(RawFd);
+    let msg = recvmsg::<()>(
+        socket.as_raw_fd(),
+        &mut iov,
+        Some(&mut cmsg),
+        MsgFlags::MSG_CMSG_CLOEXEC,
+    )?;
+    for cmsg in msg.cmsgs()? {
+        if let ControlMessageOwned::ScmRights(fds) = cmsg {
+            if let Some(&fd) = fds.first() {
+                return Ok(unsafe { OwnedFd::from_raw_fd(fd) });
+            }
+        }
+    }
+    Err(Errno::EBADMSG)
+}
+
+fn idmap_error(err: Errno) -> Error {
+    match err {
+        Errno::ENOSYS | Errno::EINVAL | Errno::EOPNOTSUPP => {
+            format!("Idmapped mounts unsupported: {err}").into()
+        }
+        _ => format!("Cannot create idmapped mount: {err}").into(),
+    }
+}
+
+/// Remounts bind mount as read-only.
+///
+/// Flags of original mount are preserved, because locked flags cannot be
+/// cleared inside user namespace.
+fn remount_readonly(target: &Path) -> Result<(), Error> {
+    let stat = statvfs(target)?;
+    let mut flags = MsFlags::MS_BIND | MsFlags::MS_REMOUNT | MsFlags::MS_RDONLY;
+    for (fs_flag, ms_flag) in [
+        (FsFlags::ST_NOSUID, MsFlags::MS_NOSUID),
+        (FsFlags::ST_NODEV, MsFlags::MS_NODEV),
+        (FsFlags::ST_NOEXEC, MsFlags::MS_NOEXEC),
+        (FsFlags::ST_NOATIME, MsFlags::MS_NOATIME),
+        (FsFlags::ST_NODIRATIME, MsFlags::MS_NODIRATIME),
+        (FsFlags::ST_RELATIME, MsFlags::MS_RELATIME),
+    ] {
+        if stat.flags().contains(fs_flag) {
+            flags |= ms_flag;
+        }
+    }
+    Ok(mount(
+        None::<&str>,
+        target,
+        None::<&str>,
+        flags,
+        None::<&str>,
+    )?)
+}
+
+/// Remounts bind mount and all its submounts as read-only.
+fn remount_readonly_recursive(target: &Path) -> Result<(), Error> {
+    let fd = nix::fcntl::open(
+        target,
+        OFlag::O_PATH | OFlag::O_CLOEXEC,
+        nix::sys::stat::Mode::empty(),
+    )?;
+    let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+    let attr = MountAttr {
+        attr_set: MOUNT_ATTR_RDONLY,
+        ..Default::default()
+    };
+    match mount_setattr(&fd, nix::libc::AT_RECURSIVE as nix::libc::c_uint, &attr) {
+        Ok(()) => Ok(()),
+        Err(Errno::ENOSYS) => {
+            remount_readonly(target)?;
+            for info in MountInfo::read("/proc/self/mountinfo")? {
+                if info.mount_point != target && info.mount_point.starts_with(target) {
+                    remount_readonly(&info.mount_point)?;
+                }
+            }
+            Ok(())
+        }
+        Err(v) => Err(format!("Cannot make {target:?} read-only: {v}").into()),
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct BaseMounts {
+    tmp_size: Option<usize>,
+    readonly_proc_sys: bool,
+    writable_cgroup: bool,
+    ptmx: bool,
+}
 
 impl BaseMounts {
     pub fn new() -> Self {
-        Self {}
+        Self {
+            tmp_size: None,
+            readonly_proc_sys: false,
+            writable_cgroup: false,
+            ptmx: true,
+        }
+    }
+
+    /// Creates `/dev/ptmx` symlink to `pts/ptmx` of container devpts.
+    ///
+    /// Enabled by default, so `posix_openpt` works inside container.
+    pub fn ptmx(mut self, ptmx: bool) -> Self {
+        self.ptmx = ptmx;
+        self
+    }
+
+    /// Mounts tmpfs with specified size limit in bytes at `/tmp`.
+    ///
+    /// Writes to `/tmp` fail with ENOSPC when limit is reached instead of
+    /// consuming space of rootfs. Non-empty `/tmp` of rootfs is never shadowed.
+    pub fn tmp(mut self, size: usize) -> Self {
+        self.tmp_size = Some(size);
+        self
+    }
+
+    /// Makes `/proc/sys` read-only for container.
+    ///
+    /// Sysctls remain readable. Use `ContainerOptions::sysctl` to set values
+    /// at container start, they are applied before mounts.
+    pub fn readonly_proc_sys(mut self, readonly: bool) -> Self {
+        self.readonly_proc_sys = readonly;
+        self
+    }
+
+    /// Mounts `/sys/fs/cgroup` read-write for nested cgroup management.
+    ///
+    /// Container runs in own cgroup namespace, so mount is rooted at cgroup of
+    /// init process and processes in container can manage only its subtree. To make
+    /// subtree writable from container user namespace, container cgroup
+    /// directory and its `cgroup.procs`, `cgroup.threads` and
+    /// `cgroup.subtree_control` files should be owned by user mapped as
+    /// container root, and cgroup2 should be mounted on host with `nsdelegate`
+    /// option, so container cannot modify limits of its own cgroup.
+    ///
+    /// Processes in container can move themselves between cgroups of subtree
+    /// and create new cgroups, so limits of child cgroups should not be
+    /// trusted by host.
+    pub fn writable_cgroup(mut self, writable: bool) -> Self {
+        self.writable_cgroup = writable;
+        self
     }
 }
 
@@ -90,6 +688,17 @@ impl Mount for BaseMounts {
             MsFlags::MS_NOEXEC | MsFlags::MS_NOSUID | MsFlags::MS_NODEV,
             None,
         )?;
+        if self.readonly_proc_sys {
+            let target = rootfs.join("proc/sys");
+            mount(
+                Some(&target),
+                &target,
+                None::<&str>,
+                MsFlags::MS_BIND,
+                None::<&str>,
+            )?;
+            remount_readonly(&target)?;
+        }
         setup_mount(
             rootfs,
             "tmpfs",
@@ -106,6 +715,9 @@ impl Mount for BaseMounts {
             MsFlags::MS_NOSUID | MsFlags::MS_NOEXEC,
             Some("newinstance,ptmxmode=0666,mode=0620"),
         )?;
+        if self.ptmx {
+            symlink("pts/ptmx", rootfs.join("dev/ptmx"))?;
+        }
         setup_mount(
             rootfs,
             "tmpfs",
@@ -114,6 +726,22 @@ impl Mount for BaseMounts {
             MsFlags::MS_NOEXEC | MsFlags::MS_NOSUID | MsFlags::MS_NODEV,
             Some("mode=1777,size=65536k"),
         )?;
+        if let Some(size) = self.tmp_size {
+            let target = rootfs.join("tmp");
+            if let Ok(mut entries) = std::fs::read_dir(&target) {
+                if entries.next().is_some() {
+                    Err(format!("Cannot mount tmpfs over non-empty {target:?}"))?
+                }
+            }
+            setup_mount(
+                rootfs,
+                "tmpfs",
+                "/tmp",
+                "tmpfs",
+                MsFlags::MS_NOSUID | MsFlags::MS_NODEV,
+                Some(&format!("mode=1777,size={size}")),
+            )?;
+        }
         setup_mount(
             rootfs,
             "mqueue",
@@ -122,31 +750,267 @@ impl Mount for BaseMounts {
             MsFlags::MS_NOEXEC | MsFlags::MS_NOSUID | MsFlags::MS_NODEV,
             None,
         )?;
+        let mut cgroup_flags =
+            MsFlags::MS_NOEXEC | MsFlags::MS_NOSUID | MsFlags::MS_NODEV | MsFlags::MS_RELATIME;
+        if !self.writable_cgroup {
+            cgroup_flags |= MsFlags::MS_RDONLY;
+        }
         setup_mount(
             rootfs,
             "cgroup",
             "/sys/fs/cgroup",
             "cgroup2",
-            MsFlags::MS_NOEXEC
-                | MsFlags::MS_NOSUID
-                | MsFlags::MS_NODEV
-                | MsFlags::MS_RELATIME
-                | MsFlags::MS_RDONLY,
+            cgroup_flags,
             None,
         )?;
         Ok(())
     }
 }
 
-pub(crate) fn setup_mount_namespace(container: &Container) -> Result<(), Error> {
+/// Represents procfs mount with hardening applied by OCI runtimes.
+///
+/// Mounts proc at `/proc`, makes kernel tunables read-only and masks files
+/// that expose host information. Paths missing on current kernel are skipped.
+/// Use it instead of proc mounted by `BaseMounts`, or add it after them, so
+/// it is mounted on top.
+#[derive(Debug, Clone)]
+pub struct HardenedProcMount {
+    /// Paths inside container that are remounted read-only.
+    pub readonly_paths: Vec<PathBuf>,
+    /// Paths inside container that are hidden.
+    pub masked_paths: Vec<PathBuf>,
+}
+
+impl HardenedProcMount {
+    pub fn new() -> Self {
+        Self {
+            readonly_paths: [
+                "/proc/bus",
+                "/proc/fs",
+                "/proc/irq",
+                "/proc/sys",
+                "/proc/sysrq-trigger",
+            ]
+            .map(PathBuf::from)
+            .to_vec(),
+            masked_paths: [
+                "/proc/acpi",
+                "/proc/kcore",
+                "/proc/keys",
+                "/proc/latency_stats",
+                "/proc/timer_list",
+                "/proc/timer_stats",
+                "/proc/sched_debug",
+                "/proc/scsi",
+            ]
+            .map(PathBuf::from)
+            .to_vec(),
+        }
+    }
+
+    /// Adds path inside container that is remounted read-only.
+    pub fn readonly(mut self, path: impl Into<PathBuf>) -> Self {
+        self.readonly_paths.push(path.into());
+        self
+    }
+
+    /// Adds path inside container that is hidden.
+    ///
+    /// Directories are covered with empty read-only tmpfs and files are
+    /// covered with `/dev/null`.
+    pub fn mask(mut self, path: impl Into<PathBuf>) -> Self {
+        self.masked_paths.push(path.into());
+        self
+    }
+}
+
+impl Default for HardenedProcMount {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Mount for HardenedProcMount {
+    fn mount(&self, rootfs: &Path) -> Result<(), Error> {
+        setup_mount(
+            rootfs,
+            "proc",
+            "/proc",
+            "proc",
+            MsFlags::MS_NOEXEC | MsFlags::MS_NOSUID | MsFlags::MS_NODEV,
+            None,
+        )?;
+        let resolve = |path: &PathBuf| rootfs.join(path.strip_prefix("/").unwrap_or(path));
+        for path in &self.readonly_paths {
+            let target = resolve(path);
+            if !target.exists() {
+                continue;
+            }
+            mount(
+                Some(&target),
+                &target,
+                None::<&str>,
+                MsFlags::MS_BIND | MsFlags::MS_REC,
+                None::<&str>,
+            )
+            .map_err(|v| format!("Cannot bind {path:?}: {v}"))?;
+            remount_readonly(&target).map_err(|v| format!("Cannot remount {path:?}: {v}"))?;
+        }
+        for path in &self.masked_paths {
+            let target = resolve(path);
+            let metadata = match target.metadata() {
+                Ok(v) => v,
+                Err(_) => continue,
+            };
+            if metadata.is_dir() {
+                mount(
+                    Some("tmpfs"),
+                    &target,
+                    Some("tmpfs"),
+                    MsFlags::MS_RDONLY,
+                    Some("size=0"),
+                )
+            } else {
+                mount(
+                    Some("/dev/null"),
+                    &target,
+                    None::<&str>,
+                    MsFlags::MS_BIND,
+                    None::<&str>,
+                )
+            }
+            .map_err(|v| format!("Cannot mask {path:?}: {v}"))?;
+        }
+        Ok(())
+    }
+}
+
+pub(crate) fn setup_mount_namespace(
+    container: &Container,
+    console: Option<&Path>,
+) -> Result<(), Error> {
     // First of all make all changes are private for current root.
     remount_private_root(&container.rootfs)?;
     // Setup mounts.
     for mount in &container.mounts {
-        mount.mount(&container.rootfs)?;
+        mount
+            .mount(&container.rootfs)
+            .map_err(ChildError::context(format_args!(
+                "Mount failed for {mount:?}"
+            )))?;
+    }
+    if container.etc_hosts {
+        setup_etc_hosts(&container.rootfs, &container.hostname)?;
+    }
+    if let Some(console) = console {
+        setup_console(&container.rootfs, console)?;
+    }
+    // Change root.
+    match container.root_method {
+        RootMethod::PivotRoot => pivot_root(&container.rootfs)?,
+        RootMethod::Chroot => chroot(&container.rootfs)?,
+    }
+    for mount in &container.mounts {
+        mount.finalize().map_err(ChildError::context(format_args!(
+            "Mount failed for {mount:?}"
+        )))?;
+    }
+    Ok(())
+}
+
+/// Bind mounts generated `/etc/hostname` and `/etc/hosts` over rootfs files.
+///
+/// Files are written to tmpfs temporarily mounted over `/etc`, so rootfs is
+/// not modified and can be read-only. Files stay available after tmpfs is
+/// detached since they are still referenced by bind mounts.
+fn setup_etc_hosts(rootfs: &Path, hostname: &str) -> Result<(), Error> {
+    let etc = rootfs.join("etc");
+    let files = [
+        ("hostname", format!("{hostname}\n")),
+        (
+            "hosts",
+            format!("127.0.0.1\tlocalhost {hostname}\n::1\tlocalhost {hostname}\n"),
+        ),
+    ];
+    create_dir_all(&etc)?;
+    for (name, _) in &files {
+        let target = etc.join(name);
+        if !target.exists() {
+            File::create(&target)
+                .map_err(|v| format!("Cannot create /etc/{name} in rootfs: {v}"))?;
+        }
+    }
+    // Keep original directory reachable while it is hidden by tmpfs.
+    let dir = open(
+        &etc,
+        OFlag::O_PATH | OFlag::O_DIRECTORY | OFlag::O_CLOEXEC,
+        nix::sys::stat::Mode::empty(),
+    )?;
+    let dir = unsafe { OwnedFd::from_raw_fd(dir) };
+    mount(
+        Some("tmpfs"),
+        &etc,
+        Some("tmpfs"),
+        MsFlags::MS_NOSUID | MsFlags::MS_NODEV | MsFlags::MS_NOEXEC,
+        Some("mode=755,size=64k"),
+    )?;
+    let result = files
+        .iter()
+        .try_for_each(|(name, content)| -> Result<(), Error> {
+            std::fs::write(etc.join(name), content)?;
+            mount(
+                Some(&etc.join(name)),
+                &PathBuf::from(format!("/proc/self/fd/{}/{name}", dir.as_raw_fd())),
+                None::<&str>,
+                MsFlags::MS_BIND,
+                None::<&str>,
+            )
+            .map_err(|v| format!("Cannot mount /etc/{name}: {v}").into())
+        });
+    umount2(&etc, MntFlags::MNT_DETACH)?;
+    result
+}
+
+/// Bind mounts terminal at `/dev/console` of rootfs.
+fn setup_console(rootfs: &Path, console: &Path) -> Result<(), Error> {
+    let target = rootfs.join("dev/console");
+    if !target.exists() {
+        create_dir_all(rootfs.join("dev"))?;
+        File::create(&target).map_err(|v| format!("Cannot create /dev/console in rootfs: {v}"))?;
     }
-    // Pivot root.
-    pivot_root(&container.rootfs)
+    mount(
+        Some(console),
+        &target,
+        None::<&str>,
+        MsFlags::MS_BIND,
+        None::<&str>,
+    )
+    .map_err(|v| format!("Cannot mount /dev/console: {v}"))?;
+    Ok(())
+}
+
+/// Changes root directory of process that joined container mount namespace.
+///
+/// Joining mount namespace resets root to namespace root, which is host root
+/// when container uses `RootMethod::Chroot`.
+pub(crate) fn join_root(container: &Container, init_pid: Pid) -> Result<(), Error> {
+    match container.root_method {
+        RootMethod::PivotRoot => Ok(()),
+        RootMethod::Chroot => chroot(Path::new(&format!("/proc/{init_pid}/root"))),
+    }
+}
+
+/// Represents method of changing root directory of container.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum RootMethod {
+    /// Uses `pivot_root` and detaches original root.
+    #[default]
+    PivotRoot,
+    /// Uses `chroot` for environments where `pivot_root` is unavailable.
+    ///
+    /// This method is less secure: original root stays mounted in container
+    /// mount namespace and process with `CAP_SYS_CHROOT` can escape chroot.
+    Chroot,
 }
 
 fn remount_private_root(path: &Path) -> Result<(), Error> {
@@ -194,6 +1058,12 @@ fn pivot_root(path: &Path) -> Result<(), Error> {
     Ok(fchdir(new_root)?)
 }
 
+fn chroot(path: &Path) -> Result<(), Error> {
+    nix::unistd::chdir(path)?;
+    nix::unistd::chroot(".")?;
+    Ok(nix::unistd::chdir("/")?)
+}
+
 fn setup_mount(
     rootfs: &Path,
     source: &str,
@@ -206,3 +1076,116 @@ fn setup_mount(
     ignore_kind(create_dir(&target), ErrorKind::AlreadyExists)?;
     Ok(mount(source.into(), &target, fstype.into(), flags, data)?)
 }
+
+/// Checks that filesystem of path can be used as overlay upperdir.
+///
+/// Overlayfs fails with `EINVAL` for such filesystems, so descriptive error
+/// is returned before mount.
+fn check_upper_fs(kind: &str, path: &Path) -> Result<(), Error> {
+    let fs_type = statfs(path)
+        .map_err(|v| format!("Cannot statfs overlay {kind} {path:?}: {v}"))?
+        .filesystem_type();
+    let name = match fs_type {
+        OVERLAYFS_SUPER_MAGIC => "overlayfs",
+        NFS_SUPER_MAGIC => "nfs",
+        SMB_SUPER_MAGIC => "smb",
+        MSDOS_SUPER_MAGIC => "vfat",
+        ISOFS_SUPER_MAGIC => "iso9660",
+        CRAMFS_MAGIC => "cramfs",
+        _ => return Ok(()),
+    };
+    Err(format!("Overlay {kind} {path:?} is on {name}, which is not supported as upperdir").into())
+}
+
+fn check_dir(kind: &str, path: &Path) -> Result<(), Error> {
+    match std::fs::metadata(path) {
+        Ok(v) if v.is_dir() => Ok(()),
+        Ok(_) => Err(format!("Overlay {kind} is not a directory: {path:?}").into()),
+        Err(v) => Err(format!("Invalid overlay {kind} {path:?}: {v}").into()),
+    }
+}
+
+/// Represents entry of `/proc/<pid>/mountinfo`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MountInfo {
+    pub mount_id: u32,
+    pub parent_id: u32,
+    /// Device number in form of `major:minor`.
+    pub device: String,
+    /// Root of mount within filesystem.
+    pub root: PathBuf,
+    pub mount_point: PathBuf,
+    /// Per-mount options.
+    pub options: String,
+    /// Optional fields like `shared:N` or `master:N`.
+    pub optional_fields: Vec<String>,
+    pub fstype: String,
+    pub source: String,
+    /// Per-superblock options.
+    pub super_options: String,
+}
+
+impl MountInfo {
+    /// Reads and parses all entries of mountinfo file.
+    pub fn read(path: impl AsRef<Path>) -> Result<Vec<Self>, Error> {
+        let path = path.as_ref();
+        let content =
+            std::fs::read_to_string(path).map_err(|v| format!("Cannot read {path:?}: {v}"))?;
+        content.lines().map(Self::parse).collect()
+    }
+
+    /// Parses single line of mountinfo file.
+    pub fn parse(line: &str) -> Result<Self, Error> {
+        let invalid = || format!("Invalid mountinfo line: {line:?}");
+        let mut fields = line.split(' ');
+        let mut next = || fields.next().ok_or_else(invalid);
+        let mount_id = next()?.parse().map_err(|_| invalid())?;
+        let parent_id = next()?.parse().map_err(|_| invalid())?;
+        let device = next()?.to_owned();
+        let root = unescape_mountinfo(next()?).into();
+        let mount_point = unescape_mountinfo(next()?).into();
+        let options = next()?.to_owned();
+        let mut optional_fields = Vec::new();
+        loop {
+            match next()? {
+                "-" => break,
+                v => optional_fields.push(v.to_owned()),
+            }
+        }
+        let fstype = unescape_mountinfo(next()?).to_string_lossy().into_owned();
+        let source = unescape_mountinfo(next()?).to_string_lossy().into_owned();
+        let super_options = next()?.to_owned();
+        Ok(Self {
+            mount_id,
+            parent_id,
+            device,
+            root,
+            mount_point,
+            options,
+            optional_fields,
+            fstype,
+            source,
+            super_options,
+        })
+    }
+}
+
+/// Decodes octal escapes used by kernel in mountinfo fields.
+fn unescape_mountinfo(value: &str) -> OsString {
+    let mut result = Vec::with_capacity(value.len());
+    let bytes = value.as_bytes();
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'\\' && i + 3 < bytes.len() {
+            let digits = std::str::from_utf8(&bytes[i + 1..i + 4]).unwrap_or_default();
+            if let Ok(v) = u8::from_str_radix(digits, 8) {
+                result.push(v);
+                i += 4;
+                continue;
+            }
+        }
+        result.push(bytes[i]);
+        i += 1;
+    }
+    OsString::from_vec(result)
+}
@@ -1,12 +1,31 @@
 use std::fmt::Debug;
 use std::fs::File;
-use std::io::Write as _;
+use std::io::{ErrorKind, Read as _, Write as _};
+use std::net::Ipv4Addr;
+use std::os::fd::{AsFd, AsRawFd, FromRawFd, OwnedFd};
+use std::os::unix::process::CommandExt;
 use std::panic::RefUnwindSafe;
 use std::path::PathBuf;
+use std::time::{Duration, Instant};
 
-use crate::{Error, Pid};
+use nix::errno::Errno;
+use nix::fcntl::OFlag;
+use nix::poll::{poll, PollFd, PollFlags, PollTimeout};
+use nix::unistd::AccessFlags;
 
-pub trait NetworkHandle: Send + Sync + Debug + RefUnwindSafe {}
+use crate::{Cgroup, Error, Pid};
+
+pub trait NetworkHandle: Send + Sync + Debug + RefUnwindSafe {
+    /// Returns gateway address of container network.
+    fn gateway(&self) -> Option<Ipv4Addr> {
+        None
+    }
+
+    /// Returns address of container in container network.
+    fn container_ip(&self) -> Option<Ipv4Addr> {
+        None
+    }
+}
 
 pub trait NetworkManager: Send + Sync + Debug + RefUnwindSafe {
     fn run_network(&self, pid: Pid) -> Result<Option<Box<dyn NetworkHandle>>, Error>;
@@ -17,14 +36,76 @@ pub trait NetworkManager: Send + Sync + Debug + RefUnwindSafe {
 #[derive(Debug)]
 pub struct Slirp4NetnsManager {
     pub binary: PathBuf,
+    /// Cgroup for slirp4netns process.
+    ///
+    /// Helper moves itself to cgroup before exec, so its memory and CPU usage
+    /// are accounted from start. Cgroup is created if it does not exist and
+    /// should be able to contain processes (should not have subtree
+    /// controllers enabled).
+    pub cgroup: Option<Cgroup>,
+    /// Network address and prefix length of container network.
+    ///
+    /// Gateway, nameserver and container use `.2`, `.3` and `.100` addresses
+    /// of network, so prefix length should be at most 25.
+    pub cidr: (Ipv4Addr, u8),
+    /// Amount of spawn retries after transient failures.
+    pub spawn_retries: usize,
+    /// Delay before first retry, doubled for each next retry.
+    pub retry_delay: Duration,
+    /// Timeout for helper to become ready.
+    ///
+    /// If set, helper reports readiness using `--ready-fd` and container start
+    /// fails if it is not ready in time.
+    pub ready_timeout: Option<Duration>,
 }
 
 impl Slirp4NetnsManager {
     pub fn new() -> Self {
         Self {
             binary: "/bin/slirp4netns".into(),
+            cgroup: None,
+            cidr: (Ipv4Addr::new(10, 0, 2, 0), 24),
+            spawn_retries: 3,
+            retry_delay: Duration::from_millis(100),
+            ready_timeout: None,
+        }
+    }
+
+    fn check_binary(&self) -> Result<(), Error> {
+        match nix::unistd::access(&self.binary, AccessFlags::X_OK) {
+            Ok(()) => Ok(()),
+            Err(Errno::ENOENT) => Err(format!(
+                "slirp4netns not found at {:?}; install it or set a custom binary path",
+                self.binary,
+            )
+            .into()),
+            Err(v) => {
+                Err(format!("slirp4netns at {:?} is not executable: {v}", self.binary).into())
+            }
         }
     }
+
+    fn spawn(&self, command: &mut std::process::Command) -> Result<std::process::Child, Error> {
+        let mut delay = self.retry_delay;
+        let mut retries = self.spawn_retries;
+        loop {
+            match command.spawn() {
+                Ok(v) => return Ok(v),
+                Err(err) if retries > 0 && is_transient(&err) => {
+                    std::thread::sleep(delay);
+                    delay *= 2;
+                    retries -= 1;
+                }
+                Err(err) => return Err(format!("Cannot start slirp4netns: {err}").into()),
+            }
+        }
+    }
+
+    fn host_addr(&self, host: u32) -> Ipv4Addr {
+        let (addr, prefix) = self.cidr;
+        let mask = u32::MAX.checked_shl(32 - prefix as u32).unwrap_or(0);
+        Ipv4Addr::from(u32::from(addr) & mask | host)
+    }
 }
 
 impl Default for Slirp4NetnsManager {
@@ -35,27 +116,94 @@ impl Default for Slirp4NetnsManager {
 
 impl NetworkManager for Slirp4NetnsManager {
     fn run_network(&self, pid: Pid) -> Result<Option<Box<dyn NetworkHandle>>, Error> {
-        let handle = std::process::Command::new(&self.binary)
+        let (addr, prefix) = self.cidr;
+        if !(1..=25).contains(&prefix) {
+            return Err(format!("Invalid slirp4netns network prefix length: {prefix}").into());
+        }
+        self.check_binary()?;
+        let mut command = std::process::Command::new(&self.binary);
+        command
             .arg("--configure")
             .arg("--mtu=65520")
+            .arg(format!("--cidr={addr}/{prefix}"))
             .arg("--disable-host-loopback")
             .arg(pid.to_string())
-            .arg("tap0")
-            .spawn()?;
-        Ok(Some(Box::new(Slirp4NetnsHandle { handle })))
+            .arg("tap0");
+        let procs = match &self.cgroup {
+            Some(cgroup) => {
+                cgroup.create()?;
+                let procs = File::options()
+                    .write(true)
+                    .open(cgroup.as_path().join("cgroup.procs"))
+                    .map_err(|v| format!("Cannot open slirp4netns cgroup: {v}"))?;
+                let fd = procs.as_raw_fd();
+                // Writing 0 moves current process, no allocation is needed.
+                unsafe {
+                    command.pre_exec(move || {
+                        Errno::result(nix::libc::write(fd, b"0".as_ptr().cast(), 1))?;
+                        Ok(())
+                    })
+                };
+                Some(procs)
+            }
+            None => None,
+        };
+        let ready = match self.ready_timeout {
+            Some(timeout) => {
+                let (rx, tx) = nix::unistd::pipe2(OFlag::O_CLOEXEC)?;
+                let fd = tx.as_raw_fd();
+                command.arg(format!("--ready-fd={fd}"));
+                // Descriptor is inherited only by helper process.
+                unsafe {
+                    command.pre_exec(move || {
+                        Errno::result(nix::libc::fcntl(fd, nix::libc::F_SETFD, 0))?;
+                        Ok(())
+                    })
+                };
+                Some((File::from(rx), tx, timeout))
+            }
+            None => None,
+        };
+        let handle = self.spawn(&mut command)?;
+        drop(procs);
+        let mut handle = Slirp4NetnsHandle {
+            handle,
+            gateway: self.host_addr(2),
+            container_ip: self.host_addr(100),
+        };
+        if let Some((rx, tx, timeout)) = ready {
+            drop(tx);
+            wait_ready(rx, timeout).map_err(|v| match handle.handle.try_wait() {
+                Ok(Some(status)) => format!("slirp4netns exited with {status}: {v}"),
+                _ => format!("slirp4netns is not ready: {v}"),
+            })?;
+        }
+        Ok(Some(Box::new(handle)))
     }
 
     fn set_network(&self) -> Result<(), Error> {
-        Ok(File::create("/etc/resolv.conf")?.write_all("nameserver 10.0.2.3".as_bytes())?)
+        let nameserver = self.host_addr(3);
+        Ok(File::create("/etc/resolv.conf")?
+            .write_all(format!("nameserver {nameserver}\n").as_bytes())?)
     }
 }
 
 #[derive(Debug)]
 pub struct Slirp4NetnsHandle {
     handle: std::process::Child,
+    gateway: Ipv4Addr,
+    container_ip: Ipv4Addr,
 }
 
-impl NetworkHandle for Slirp4NetnsHandle {}
+impl NetworkHandle for Slirp4NetnsHandle {
+    fn gateway(&self) -> Option<Ipv4Addr> {
+        Some(self.gateway)
+    }
+
+    fn container_ip(&self) -> Option<Ipv4Addr> {
+        Some(self.container_ip)
+    }
+}
 
 impl Drop for Slirp4NetnsHandle {
     fn drop(&mut self) {
@@ -63,3 +211,53 @@ impl Drop for Slirp4NetnsHandle {
         let _ = self.handle.wait();
     }
 }
+
+fn is_transient(err: &std::io::Error) -> bool {
+    matches!(
+        err.kind(),
+        ErrorKind::WouldBlock | ErrorKind::Interrupted | ErrorKind::OutOfMemory
+    ) || err.raw_os_error() == Some(nix::libc::ETXTBSY)
+}
+
+/// Waits for helper to write readiness byte to pipe.
+fn wait_ready(mut rx: File, timeout: Duration) -> Result<(), Error> {
+    let deadline = Instant::now() + timeout;
+    loop {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        let remaining = PollTimeout::try_from(remaining).unwrap_or(PollTimeout::MAX);
+        let mut fds = [PollFd::new(rx.as_fd(), PollFlags::POLLIN)];
+        match poll(&mut fds, remaining) {
+            Ok(0) => return Err(format!("timed out after {timeout:?}").into()),
+            Ok(_) => break,
+            Err(Errno::EINTR) => continue,
+            Err(err) => return Err(err.into()),
+        }
+    }
+    let mut buf = [0; 1];
+    match rx.read(&mut buf)? {
+        0 => Err("pipe closed before ready".into()),
+        _ => Ok(()),
+    }
+}
+
+/// Brings up loopback interface in current network namespace.
+pub(crate) fn setup_loopback() -> Result<(), Error> {
+    let fd = Errno::result(unsafe {
+        nix::libc::socket(
+            nix::libc::AF_INET,
+            nix::libc::SOCK_DGRAM | nix::libc::SOCK_CLOEXEC,
+            0,
+        )
+    })?;
+    let socket = unsafe { OwnedFd::from_raw_fd(fd) };
+    let mut req: nix::libc::ifreq = unsafe { std::mem::zeroed() };
+    for (dst, src) in req.ifr_name.iter_mut().zip(b"lo") {
+        *dst = *src as nix::libc::c_char;
+    }
+    Errno::result(unsafe {
+        nix::libc::ioctl(socket.as_raw_fd(), nix::libc::SIOCGIFFLAGS, &mut req)
+    })?;
+    unsafe { req.ifr_ifru.ifru_flags |= nix::libc::IFF_UP as nix::libc::c_short };
+    Errno::result(unsafe { nix::libc::ioctl(socket.as_raw_fd(), nix::libc::SIOCSIFFLAGS, &req) })?;
+    Ok(())
+}
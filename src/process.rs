@@ -1,22 +1,34 @@
 use std::convert::Infallible;
 use std::ffi::CString;
-use std::os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::fs::File;
+use std::io::Read;
+use std::os::fd::{AsFd, AsRawFd, BorrowedFd, FromRawFd, IntoRawFd, OwnedFd, RawFd};
 use std::panic::catch_unwind;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
 
+use nix::errno::Errno;
 use nix::fcntl::OFlag;
+use nix::libc::mode_t;
+use nix::poll::{poll, PollFd, PollFlags, PollTimeout};
 use nix::sched::CloneFlags;
+use nix::sys::resource::setrlimit;
+use nix::sys::signal::{sigprocmask, SigSet, SigmaskHow};
+use nix::sys::stat::{umask, Mode};
 use nix::sys::wait::{waitpid, WaitPidFlag};
 use nix::unistd::{chdir, dup2, execvpe, fork, sethostname, ForkResult, Gid, Pid, Uid};
 use nix::NixPath;
 
 use crate::{
-    clone3, close_exec_from, exit_child, new_pipe, pidfd_open, read_ok, read_pid, read_result,
-    setup_mount_namespace, write_ok, write_pid, write_result, CloneArgs, CloneResult, Container,
-    Error, NetworkHandle, OwnedPid,
+    clone3, close_exec_from, exit_child, join_root, new_pipe, pidfd_open, read_exec_result,
+    read_ok, read_pid, read_result, set_child_panic_hook, setup_loopback, setup_mount_namespace,
+    write_ok, write_pid, write_result, Cgroup, CgroupCpuUsage, ChildError, CloneArgs, CloneResult,
+    Container, ContainerEventKind, Error, LandlockRuleset, Namespaces, NetworkHandle, OwnedPid,
+    ResourceLimits,
 };
 
 pub type Signal = nix::sys::signal::Signal;
+pub type Resource = nix::sys::resource::Resource;
 pub type WaitStatus = nix::sys::wait::WaitStatus;
 
 #[derive(Debug, Default)]
@@ -27,9 +39,15 @@ pub struct InitProcessOptions {
     uid: Option<Uid>,
     gid: Option<Gid>,
     cgroup: PathBuf,
+    limits: ResourceLimits,
+    umask: Option<Mode>,
     stdin: Option<OwnedFd>,
     stdout: Option<OwnedFd>,
     stderr: Option<OwnedFd>,
+    pipe_output: bool,
+    clear_signal_handlers: bool,
+    console: bool,
+    reaper: Option<Vec<Signal>>,
 }
 
 impl InitProcessOptions {
@@ -52,17 +70,44 @@ impl InitProcessOptions {
         self
     }
 
+    /// Sets user and group of init process.
+    ///
+    /// Mounts, hostname and network are configured as root of container user
+    /// namespace, and privileges are dropped to specified user right before
+    /// exec, so init process can run as unprivileged user.
     pub fn user(mut self, uid: Uid, gid: Gid) -> Self {
         self.uid = Some(uid);
         self.gid = Some(gid);
         self
     }
 
+    /// Sets child cgroup of container cgroup for init process.
+    ///
+    /// Init process is cloned directly into this cgroup, so cgroup namespace
+    /// of container is rooted at it. Controllers available in container cgroup
+    /// are enabled for child cgroups if possible. Init process without own
+    /// cgroup cannot be started when container cgroup has enabled controllers.
     pub fn cgroup(mut self, cgroup: impl Into<PathBuf>) -> Self {
         self.cgroup = cgroup.into();
         self
     }
 
+    /// Sets resource limits for process cgroup.
+    ///
+    /// Limits are applied before process is started, so process cgroup should be specified.
+    pub fn limits(mut self, limits: ResourceLimits) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    /// Sets file mode creation mask for process.
+    ///
+    /// By default mask is inherited from current process.
+    pub fn umask(mut self, mask: mode_t) -> Self {
+        self.umask = Some(Mode::from_bits_truncate(mask));
+        self
+    }
+
     pub fn stdin(mut self, fd: impl Into<OwnedFd>) -> Self {
         self.stdin = Some(fd.into());
         self
@@ -78,7 +123,61 @@ impl InitProcessOptions {
         self
     }
 
+    /// Resets signal handlers of current process to default in child process.
+    ///
+    /// Uses `CLONE_CLEAR_SIGHAND` that requires Linux 5.5+. Handlers are also
+    /// reset on exec, so this only affects child before exec. Ignored signals
+    /// stay ignored. Flag is skipped on kernels without clone3.
+    pub fn clear_signal_handlers(mut self, clear: bool) -> Self {
+        self.clear_signal_handlers = clear;
+        self
+    }
+
+    /// Runs built-in reaper as init process that starts command as its child.
+    ///
+    /// Reaper reaps orphaned zombies and forwards `SIGHUP`, `SIGINT`,
+    /// `SIGQUIT`, `SIGTERM`, `SIGUSR1`, `SIGUSR2` and `SIGWINCH` to command.
+    /// Init exits with exit code of command or with `128 + signal` if command
+    /// is killed by signal.
+    pub fn reaper(self) -> Self {
+        self.reaper_signals(vec![
+            Signal::SIGHUP,
+            Signal::SIGINT,
+            Signal::SIGQUIT,
+            Signal::SIGTERM,
+            Signal::SIGUSR1,
+            Signal::SIGUSR2,
+            Signal::SIGWINCH,
+        ])
+    }
+
+    /// Runs built-in reaper that forwards only specified signals to command.
+    ///
+    /// See `InitProcessOptions::reaper` for details.
+    pub fn reaper_signals(mut self, signals: Vec<Signal>) -> Self {
+        self.reaper = Some(signals);
+        self
+    }
+
+    /// Allocates terminal that is used as console of container.
+    ///
+    /// Terminal is mounted at `/dev/console`, init process becomes session
+    /// leader with terminal as controlling terminal and stdio. Master side of
+    /// terminal is available with `InitProcess::console`.
+    pub fn console(mut self, console: bool) -> Self {
+        self.console = console;
+        self
+    }
+
+    /// Redirects stdout and stderr to pipes that can be read with `InitProcess::stream_output`.
+    pub fn pipe_output(mut self) -> Self {
+        self.pipe_output = true;
+        self
+    }
+
     pub fn start(self, container: &Container) -> Result<InitProcess, Error> {
+        #[cfg(feature = "tracing")]
+        let _span = tracing::debug_span!("init_process", command = ?self.command).entered();
         let uid = self.uid.unwrap_or(Uid::from(0));
         if !container.user_mapper.is_uid_mapped(uid) {
             return Err(format!("User {} is not mapped", uid).into());
@@ -92,18 +191,38 @@ impl InitProcessOptions {
         } else {
             "/".into()
         };
-        let command = self.command;
+        let command = match &container.init_binary {
+            Some(init_binary) => {
+                if self.reaper.is_some() {
+                    return Err("Init binary cannot be used with built-in reaper".into());
+                }
+                let init_binary = init_binary
+                    .to_str()
+                    .ok_or(format!("Invalid init binary: {init_binary:?}"))?;
+                let mut command = vec![init_binary.to_owned(), "--".to_owned()];
+                command.extend(self.command);
+                command
+            }
+            None => self.command,
+        };
         let environ = self.environ;
-        let cgroup = if self.cgroup.is_empty() {
-            None
+        let cgroup = create_process_cgroup(container, self.cgroup, &self.limits)?;
+        if cgroup.is_none() && !container.cgroup.subtree_controller_set()?.is_empty() {
+            return Err(
+                "Init process requires own cgroup when container cgroup has enabled controllers"
+                    .into(),
+            );
+        }
+        check_not_frozen(cgroup.as_ref().unwrap_or(&container.cgroup))?;
+        let mask = self.umask;
+        let reaper = self.reaper;
+        let console = if self.console {
+            Some(open_console().map_err(|v| format!("Cannot allocate console: {v}"))?)
         } else {
-            let cgroup = container.cgroup.child(self.cgroup)?;
-            cgroup.create()?;
-            Some(cgroup)
+            None
         };
         let stdin = self.stdin;
-        let stdout = self.stdout;
-        let stderr = self.stderr;
+        let (stdout, stderr, output) = output_pipes(self.pipe_output, self.stdout, self.stderr)?;
         let dev_null = if stdin.is_none() || stdout.is_none() || stderr.is_none() {
             let raw_fd =
                 nix::fcntl::open("/dev/null", OFlag::O_RDWR, nix::sys::stat::Mode::empty())?;
@@ -111,80 +230,143 @@ impl InitProcessOptions {
         } else {
             None
         };
-        let cgroup_file = container.cgroup.open()?;
+        // Init process is cloned directly into its cgroup, so it never runs
+        // without limits and container cgroup can have enabled controllers.
+        let cgroup_file = cgroup.as_ref().unwrap_or(&container.cgroup).open()?;
         let pipe = new_pipe()?;
         let child_pipe = new_pipe()?;
+        let joined = container.joined_namespaces();
         let mut clone_args = CloneArgs::default();
-        clone_args.flag_newuser();
+        if container.join_namespaces.is_none() && !container.privileged {
+            clone_args.flag_newuser();
+        }
         clone_args.flag_newns();
-        clone_args.flag_newpid();
-        clone_args.flag_newnet();
-        clone_args.flag_newipc();
-        clone_args.flag_newuts();
-        clone_args.flag_newtime();
+        if !joined.contains(Namespaces::PID) {
+            clone_args.flag_newpid();
+        }
+        if !joined.contains(Namespaces::NET) {
+            clone_args.flag_newnet();
+        }
+        if !joined.contains(Namespaces::IPC) {
+            clone_args.flag_newipc();
+        }
+        if !joined.contains(Namespaces::UTS) {
+            clone_args.flag_newuts();
+        }
+        if container.time_namespace {
+            clone_args.flag_newtime();
+        }
         clone_args.flag_newcgroup();
         clone_args.flag_into_cgroup(&cgroup_file);
-        match unsafe { clone3(&clone_args) }
-            .map_err(|v| format!("Cannot start init process: {v}"))?
-        {
+        if self.clear_signal_handlers {
+            clone_args.flag_clear_sighand();
+        }
+        // Child process must not log after clone, so mounts are reported here.
+        #[cfg(feature = "tracing")]
+        for mount in &container.mounts {
+            tracing::debug!(?mount, "mount scheduled");
+        }
+        #[cfg(feature = "tracing")]
+        tracing::debug!("cloning init process");
+        let clone_result = match container.join_namespaces {
+            Some((pid, namespaces)) => unsafe {
+                clone_in_namespaces_of(pid, namespaces, !container.privileged, clone_args)
+            },
+            None => unsafe { clone3(&clone_args) }.map_err(Error::from),
+        };
+        match clone_result.map_err(|v| format!("Cannot start init process: {v}"))? {
             CloneResult::Child => {
                 let _ = catch_unwind(move || {
+                    let tx = child_pipe.tx();
+                    set_child_panic_hook(Some(&tx));
                     drop(cgroup_file);
                     let rx = pipe.rx();
-                    let tx = child_pipe.tx();
                     exit_child(move || -> Result<Infallible, Error> {
                         // Await parent process is initialized pid.
                         read_ok(rx)?;
-                        // Unlock parent process.
-                        write_result(
-                            tx,
+                        // Report setup error to parent process.
+                        write_error(
+                            &tx,
                             move || -> Result<(), Error> {
+                                // Setup sysctls.
+                                for (key, value) in &container.sysctls {
+                                    set_sysctl(key, value)?;
+                                }
                                 // Setup mount namespace.
-                                setup_mount_namespace(container)
-                                    .map_err(|v| format!("Cannot setup mount namespace: {v}"))?;
+                                setup_mount_namespace(
+                                    container,
+                                    console.as_ref().map(|v| v.2.as_path()),
+                                )
+                                .map_err(ChildError::context("Cannot setup mount namespace"))?;
                                 // Setup uts namespace.
-                                sethostname(&container.hostname)
-                                    .map_err(|v| format!("Cannot setup hostname: {v}"))?;
-                                // Setup network.
-                                if let Some(v) = &container.network_manager {
-                                    v.set_network()?;
+                                if !joined.contains(Namespaces::UTS) {
+                                    sethostname(&container.hostname)
+                                        .map_err(ChildError::context("Cannot setup hostname"))?;
+                                }
+                                if !joined.contains(Namespaces::NET) {
+                                    // Setup loopback.
+                                    if container.loopback {
+                                        setup_loopback().map_err(ChildError::context(
+                                            "Cannot setup loopback",
+                                        ))?;
+                                    }
+                                    // Setup network.
+                                    if let Some(v) = &container.network_manager {
+                                        v.set_network()?;
+                                    }
                                 }
                                 // Setup stdio.
-                                dup2(
-                                    stdin.as_ref().or(dev_null.as_ref()).unwrap().as_raw_fd(),
-                                    RawFd::from(0),
-                                )?;
-                                dup2(
-                                    stdout.as_ref().or(dev_null.as_ref()).unwrap().as_raw_fd(),
-                                    RawFd::from(1),
-                                )?;
-                                dup2(
-                                    stderr.as_ref().or(dev_null.as_ref()).unwrap().as_raw_fd(),
-                                    RawFd::from(2),
-                                )?;
+                                if let Some((_, slave, _)) = &console {
+                                    setup_controlling_terminal(slave)
+                                        .map_err(ChildError::context("Cannot setup console"))?;
+                                } else {
+                                    dup2(
+                                        stdin.as_ref().or(dev_null.as_ref()).unwrap().as_raw_fd(),
+                                        RawFd::from(0),
+                                    )?;
+                                    dup2(
+                                        stdout.as_ref().or(dev_null.as_ref()).unwrap().as_raw_fd(),
+                                        RawFd::from(1),
+                                    )?;
+                                    dup2(
+                                        stderr.as_ref().or(dev_null.as_ref()).unwrap().as_raw_fd(),
+                                        RawFd::from(2),
+                                    )?;
+                                }
                                 // Close file descriptors.
                                 close_exec_from(3)?;
+                                // Setup umask.
+                                if let Some(mask) = mask {
+                                    umask(mask);
+                                }
                                 // Setup workdir.
                                 chdir(&work_dir)
-                                    .map_err(|v| format!("Cannot change directory: {v}"))?;
+                                    .map_err(ChildError::context("Cannot change directory"))?;
                                 // Setup user.
                                 container
                                     .user_mapper
                                     .set_user(uid, gid)
-                                    .map_err(|v| format!("Cannot set current user: {v}"))?;
+                                    .map_err(ChildError::context("Cannot set current user"))?;
+                                // Setup parent death signal, it is cleared on user change.
+                                if container.kill_on_parent_death {
+                                    Errno::result(unsafe {
+                                        nix::libc::prctl(
+                                            nix::libc::PR_SET_PDEATHSIG,
+                                            nix::libc::SIGKILL,
+                                        )
+                                    })
+                                    .map_err(
+                                        ChildError::context("Cannot set parent death signal"),
+                                    )?;
+                                }
                                 Ok(())
                             }(),
-                        )??;
-                        // Prepare exec arguments.
-                        let filename = CString::new(command[0].as_bytes())?;
-                        let argv = Result::<Vec<_>, _>::from_iter(
-                            command.iter().map(|v| CString::new(v.as_bytes())),
                         )?;
-                        let envp = Result::<Vec<_>, _>::from_iter(
-                            environ.iter().map(|v| CString::new(v.as_bytes())),
-                        )?;
-                        // Run process.
-                        Ok(execvpe(&filename, &argv, &envp)?)
+                        // Run process, pipe is closed on successful exec.
+                        match &reaper {
+                            Some(signals) => run_reaper(tx, &command, &environ, signals),
+                            None => write_error(&tx, execute(&command, &environ)),
+                        }
                     }())
                 });
                 unsafe { nix::libc::_exit(2) }
@@ -202,28 +384,34 @@ impl InitProcessOptions {
                 let rx = child_pipe.rx();
                 let tx = pipe.tx();
                 // Map user.
-                container
-                    .user_mapper
-                    .run_map_user(child.as_raw())
-                    .map_err(|v| format!("Cannot setup user namespace: {v}"))?;
-                // Setup init cgroup.
-                if let Some(cgroup) = cgroup {
-                    cgroup
-                        .add_process(child.as_raw())
-                        .map_err(|v| format!("Cannot add process to cgroup: {v}"))?;
+                #[cfg(feature = "tracing")]
+                tracing::debug!(pid = child.as_raw().as_raw(), "mapping users");
+                if container.join_namespaces.is_none() {
+                    container
+                        .user_mapper
+                        .run_map_user(child.as_raw())
+                        .map_err(|v| format!("Cannot setup user namespace: {v}"))?;
                 }
                 // Setup network namespace.
+                #[cfg(feature = "tracing")]
+                tracing::debug!("setting up network");
                 let network_handle = match &container.network_manager {
-                    Some(v) => v.run_network(child.as_raw())?,
-                    None => None,
+                    Some(v) if !joined.contains(Namespaces::NET) => {
+                        v.run_network(child.as_raw())?
+                    }
+                    _ => None,
                 };
                 // Unlock child process.
                 write_ok(tx)?;
                 // Await child process result.
-                read_result(rx)??;
+                read_exec_result(rx)??;
+                #[cfg(feature = "tracing")]
+                tracing::debug!(pid = child.as_raw().as_raw(), "init process started");
                 Ok(InitProcess {
                     pid: child.into_raw(),
-                    _network_handle: network_handle,
+                    output,
+                    console: console.map(|v| v.0),
+                    network_handle,
                 })
             }
         }
@@ -232,18 +420,79 @@ impl InitProcessOptions {
 
 pub struct InitProcess {
     pid: Pid,
-    _network_handle: Option<Box<dyn NetworkHandle>>,
+    output: PipedOutput,
+    console: Option<File>,
+    network_handle: Option<Box<dyn NetworkHandle>>,
 }
 
 impl InitProcess {
+    /// Creates init process restored by CRIU as child of current process.
+    pub(crate) fn restored(pid: Pid) -> Self {
+        Self {
+            pid,
+            output: None,
+            console: None,
+            network_handle: None,
+        }
+    }
+
     pub fn as_pid(&self) -> Pid {
         self.pid
     }
 
+    /// Returns master side of console terminal.
+    ///
+    /// Requires console to be enabled using `InitProcessOptions::console`.
+    pub fn console(&self) -> Option<&File> {
+        self.console.as_ref()
+    }
+
+    /// Sets window size of console terminal.
+    ///
+    /// Kernel sends `SIGWINCH` to foreground process group of terminal if
+    /// size is changed, so interactive programs can redraw.
+    pub fn resize_pty(&self, rows: u16, cols: u16) -> Result<(), Error> {
+        let console = self.console.as_ref().ok_or("Console is not enabled")?;
+        let size = nix::libc::winsize {
+            ws_row: rows,
+            ws_col: cols,
+            ws_xpixel: 0,
+            ws_ypixel: 0,
+        };
+        Errno::result(unsafe {
+            nix::libc::ioctl(console.as_raw_fd(), nix::libc::TIOCSWINSZ, &size)
+        })
+        .map_err(|v| format!("Cannot resize console: {v}"))?;
+        Ok(())
+    }
+
+    /// Returns handle of container network started for init process.
+    pub fn network_handle(&self) -> Option<&dyn NetworkHandle> {
+        self.network_handle.as_deref()
+    }
+
     pub fn wait(&mut self) -> Result<WaitStatus, Error> {
         Ok(waitpid(self.pid, Some(WaitPidFlag::__WALL))?)
     }
 
+    /// Drains piped stdout and stderr until EOF and waits for process exit.
+    ///
+    /// Output is passed to callbacks as soon as it arrives, so it is never
+    /// buffered entirely in memory. Callbacks run on the calling thread.
+    /// Requires output to be piped using `InitProcessOptions::pipe_output`.
+    pub fn stream_output(
+        &mut self,
+        on_stdout: impl FnMut(&[u8]),
+        on_stderr: impl FnMut(&[u8]),
+    ) -> Result<WaitStatus, Error> {
+        let (stdout, stderr) = self
+            .output
+            .take()
+            .ok_or("Init process output is not piped")?;
+        drain_output(stdout, stderr, on_stdout, on_stderr)?;
+        self.wait()
+    }
+
     pub fn options() -> InitProcessOptions {
         InitProcessOptions::new()
     }
@@ -257,9 +506,27 @@ pub struct ProcessOptions {
     uid: Option<Uid>,
     gid: Option<Gid>,
     cgroup: PathBuf,
+    limits: ResourceLimits,
+    umask: Option<Mode>,
+    nice: Option<i32>,
+    scheduler: Option<SchedPolicy>,
     stdin: Option<OwnedFd>,
     stdout: Option<OwnedFd>,
     stderr: Option<OwnedFd>,
+    pipe_output: bool,
+    inherit_stdio: bool,
+    wall_time_limit: Option<Duration>,
+    cpu_time_limit: Option<Duration>,
+    landlock: Option<LandlockRuleset>,
+    clear_signal_handlers: bool,
+    scratch_dirs: Vec<(PathBuf, usize)>,
+    resolv_conf: Option<PathBuf>,
+    default_env: bool,
+    user_name: Option<String>,
+    rlimits: Vec<(Resource, u64, u64)>,
+    no_new_privs: bool,
+    drop_capabilities: bool,
+    loginuid: Option<Uid>,
 }
 
 impl ProcessOptions {
@@ -288,11 +555,106 @@ impl ProcessOptions {
         self
     }
 
+    /// Sets user of process by name from `/etc/passwd` of container.
+    ///
+    /// User is resolved inside container, so overrides `ProcessOptions::user`.
+    /// Home directory of user is used as work directory if it is not specified.
+    pub fn user_name(mut self, name: impl Into<String>) -> Self {
+        self.user_name = Some(name.into());
+        self
+    }
+
+    /// Sets soft and hard resource limit for process.
+    pub fn rlimit(mut self, resource: Resource, soft: u64, hard: u64) -> Self {
+        self.rlimits.retain(|v| v.0 != resource);
+        self.rlimits.push((resource, soft, hard));
+        self
+    }
+
+    /// Sets audit login uid of process written to `/proc/self/loginuid`.
+    ///
+    /// Requires `CAP_AUDIT_CONTROL` in initial user namespace, so it usually
+    /// works only for privileged containers. Write failed with `EPERM` is
+    /// ignored and process keeps inherited login uid. Login uid can be set
+    /// only once if `loginuid_immutable` is enabled by audit. Kernel assigns
+    /// new session id on successful write.
+    pub fn loginuid(mut self, uid: impl Into<Uid>) -> Self {
+        self.loginuid = Some(uid.into());
+        self
+    }
+
+    /// Sets `PR_SET_NO_NEW_PRIVS`, so process cannot gain privileges with exec.
+    pub fn no_new_privs(mut self, no_new_privs: bool) -> Self {
+        self.no_new_privs = no_new_privs;
+        self
+    }
+
+    /// Drops all capabilities of process including bounding and ambient sets.
+    pub fn drop_capabilities(mut self, drop: bool) -> Self {
+        self.drop_capabilities = drop;
+        self
+    }
+
+    /// Applies conservative restrictions for running untrusted code.
+    ///
+    /// Sets following options that can be tuned separately after that:
+    ///   * `pids.max` of process cgroup to 64, so process cgroup is required;
+    ///   * `RLIMIT_NOFILE` to 1024;
+    ///   * `RLIMIT_FSIZE` to 64 MiB;
+    ///   * `PR_SET_NO_NEW_PRIVS`;
+    ///   * drops all capabilities.
+    ///
+    /// `RLIMIT_NPROC` is not set since it counts all processes of host user
+    /// across containers, so `pids.max` is used instead.
+    pub fn sandbox_defaults(mut self) -> Self {
+        self.limits.pids = Some(64);
+        self.rlimit(Resource::RLIMIT_NOFILE, 1024, 1024)
+            .rlimit(Resource::RLIMIT_FSIZE, 64 << 20, 64 << 20)
+            .no_new_privs(true)
+            .drop_capabilities(true)
+    }
+
+    /// Sets child cgroup of container cgroup for process.
+    ///
+    /// Process is cloned directly into this cgroup. Controllers available in
+    /// container cgroup are enabled for child cgroups if possible, so limits
+    /// can be applied. After that processes cannot be started in container
+    /// cgroup itself.
     pub fn cgroup(mut self, cgroup: impl Into<PathBuf>) -> Self {
         self.cgroup = cgroup.into();
         self
     }
 
+    /// Sets resource limits for process cgroup.
+    ///
+    /// Limits are applied before process is started, so process cgroup should be specified.
+    pub fn limits(mut self, limits: ResourceLimits) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    /// Sets file mode creation mask for process.
+    ///
+    /// By default mask is inherited from current process.
+    pub fn umask(mut self, mask: mode_t) -> Self {
+        self.umask = Some(Mode::from_bits_truncate(mask));
+        self
+    }
+
+    /// Sets nice value for process in range from -20 (highest priority) to 19.
+    ///
+    /// Negative values require CAP_SYS_NICE in the host user namespace.
+    pub fn nice(mut self, nice: i32) -> Self {
+        self.nice = Some(nice);
+        self
+    }
+
+    /// Sets scheduling policy for process.
+    pub fn scheduler(mut self, policy: SchedPolicy) -> Self {
+        self.scheduler = Some(policy);
+        self
+    }
+
     pub fn stdin(mut self, fd: impl Into<OwnedFd>) -> Self {
         self.stdin = Some(fd.into());
         self
@@ -308,11 +670,121 @@ impl ProcessOptions {
         self
     }
 
+    /// Sets wall-clock time limit enforced by `Process::wait_limited`.
+    ///
+    /// Time is measured from process start.
+    pub fn wall_time_limit(mut self, limit: Duration) -> Self {
+        self.wall_time_limit = Some(limit);
+        self
+    }
+
+    /// Sets CPU time limit using `RLIMIT_CPU`.
+    ///
+    /// Limit has whole-second granularity and is rounded up. Process receives
+    /// `SIGXCPU` after exceeding limit and `SIGKILL` one second later.
+    pub fn cpu_time_limit(mut self, limit: Duration) -> Self {
+        self.cpu_time_limit = Some(limit);
+        self
+    }
+
+    /// Restricts filesystem access of process with landlock ruleset.
+    ///
+    /// Ruleset is enforced right before exec, so paths are resolved inside
+    /// container. Process cannot gain privileges with setuid binaries after it.
+    pub fn landlock(mut self, ruleset: LandlockRuleset) -> Self {
+        self.landlock = Some(ruleset);
+        self
+    }
+
+    /// Resets signal handlers of current process to default in child process.
+    ///
+    /// Uses `CLONE_CLEAR_SIGHAND` that requires Linux 5.5+. Handlers are also
+    /// reset on exec, so this only affects child before exec. Ignored signals
+    /// stay ignored. Flag is skipped on kernels without clone3.
+    pub fn clear_signal_handlers(mut self, clear: bool) -> Self {
+        self.clear_signal_handlers = clear;
+        self
+    }
+
+    /// Mounts private tmpfs with specified size in bytes at path inside container.
+    ///
+    /// Process is started in its own mount namespace, so scratch directory is
+    /// not visible to other processes of container. Mounts of container made
+    /// after process start are not propagated to process.
+    pub fn scratch_dir(mut self, path: impl Into<PathBuf>, size: usize) -> Self {
+        self.scratch_dirs.push((path.into(), size));
+        self
+    }
+
+    /// Bind mounts file at path inside container over `/etc/resolv.conf`.
+    ///
+    /// Allows processes of one container to use different resolvers. Like
+    /// `ProcessOptions::scratch_dir`, process is started in its own mount
+    /// namespace.
+    pub fn resolv_conf(mut self, path: impl Into<PathBuf>) -> Self {
+        self.resolv_conf = Some(path.into());
+        self
+    }
+
+    /// Seeds environment of process with defaults of container.
+    ///
+    /// Variables are read from `/etc/environment` of container and default
+    /// `PATH` is added if missing. Variables set with `ProcessOptions::environ`
+    /// override defaults.
+    pub fn default_env(mut self, default_env: bool) -> Self {
+        self.default_env = default_env;
+        self
+    }
+
+    /// Redirects stdout and stderr to pipes that can be read with `Process::stream_output`.
+    pub fn pipe_output(mut self) -> Self {
+        self.pipe_output = true;
+        self
+    }
+
+    /// Uses stdio of current process for streams that are not specified.
+    ///
+    /// By default such streams are redirected to `/dev/null`.
+    pub fn inherit_stdio(mut self) -> Self {
+        self.inherit_stdio = true;
+        self
+    }
+
     pub fn start(
         self,
         container: &Container,
         init_process: &InitProcess,
     ) -> Result<Process, Error> {
+        self.spawn(container, init_process.pid)
+    }
+
+    /// Runs process to completion and collects its output.
+    ///
+    /// Stdout and stderr are drained concurrently, so process cannot block on
+    /// full pipe.
+    pub fn run(
+        self,
+        container: &Container,
+        init_process: &InitProcess,
+    ) -> Result<CommandOutput, Error> {
+        let mut process = self.pipe_output().start(container, init_process)?;
+        let mut stdout = Vec::new();
+        let mut stderr = Vec::new();
+        let status = process.stream_output(
+            |v| stdout.extend_from_slice(v),
+            |v| stderr.extend_from_slice(v),
+        )?;
+        Ok(CommandOutput {
+            status,
+            stdout,
+            stderr,
+        })
+    }
+
+    /// Starts process in namespaces of specified init process.
+    pub(crate) fn spawn(self, container: &Container, init_pid: Pid) -> Result<Process, Error> {
+        #[cfg(feature = "tracing")]
+        let _span = tracing::debug_span!("process", command = ?self.command).entered();
         let uid = self.uid.unwrap_or(Uid::from(0));
         if !container.user_mapper.is_uid_mapped(uid) {
             return Err(format!("User {} is not mapped", uid).into());
@@ -321,23 +793,47 @@ impl ProcessOptions {
         if !container.user_mapper.is_gid_mapped(gid) {
             return Err(format!("Group {} is not mapped", gid).into());
         }
+        let home_work_dir = self.work_dir.is_empty();
         let work_dir = if !self.work_dir.is_empty() {
             self.work_dir
         } else {
             "/".into()
         };
-        let cgroup = if self.cgroup.is_empty() {
-            None
-        } else {
-            let cgroup = container.cgroup.child(self.cgroup)?;
-            cgroup.create()?;
-            Some(cgroup)
-        };
+        let user_name = self.user_name;
+        let cgroup = create_process_cgroup(container, self.cgroup, &self.limits)?;
+        check_not_frozen(cgroup.as_ref().unwrap_or(&container.cgroup))?;
         let command = self.command;
         let environ = self.environ;
+        if let Some(nice) = self.nice {
+            if !(-20..=19).contains(&nice) {
+                return Err(format!("Invalid nice value: {nice}").into());
+            }
+        }
+        let mask = self.umask;
+        let nice = self.nice;
+        let wall_time_limit = self.wall_time_limit;
+        let cpu_time_limit = self.cpu_time_limit;
+        let landlock = self.landlock;
+        let clear_signal_handlers = self.clear_signal_handlers;
+        let scratch_dirs = self.scratch_dirs;
+        let resolv_conf = self.resolv_conf;
+        let default_env = self.default_env;
+        let rlimits = self.rlimits;
+        let no_new_privs = self.no_new_privs;
+        let drop_capabilities = self.drop_capabilities;
+        let loginuid = self.loginuid;
+        let scheduler = self.scheduler;
         let stdin = self.stdin;
-        let stdout = self.stdout;
-        let stderr = self.stderr;
+        let (stdout, stderr, output) = output_pipes(self.pipe_output, self.stdout, self.stderr)?;
+        let (stdin, stdout, stderr) = if self.inherit_stdio {
+            (
+                inherit_fd(stdin, std::io::stdin().as_fd())?,
+                inherit_fd(stdout, std::io::stdout().as_fd())?,
+                inherit_fd(stderr, std::io::stderr().as_fd())?,
+            )
+        } else {
+            (stdin, stdout, stderr)
+        };
         let dev_null = if stdin.is_none() || stdout.is_none() || stderr.is_none() {
             let raw_fd =
                 nix::fcntl::open("/dev/null", OFlag::O_RDWR, nix::sys::stat::Mode::empty())?;
@@ -345,45 +841,83 @@ impl ProcessOptions {
         } else {
             None
         };
+        let process_cgroup = cgroup.clone();
         let pid_pipe = new_pipe()?;
+        // Subchild reports start result directly to parent process.
+        let pipe = new_pipe()?;
         match unsafe { fork() }? {
             ForkResult::Child => {
                 let _ = catch_unwind(move || -> Result<(), Error> {
+                    set_child_panic_hook(None);
                     let pid_tx = pid_pipe.tx();
                     let cgroup_file = match cgroup {
                         Some(v) => v.open(),
                         None => container.cgroup.open(),
                     }?;
                     // Enter namespaces.
-                    let pidfd = pidfd_open(init_process.pid)?;
-                    let flags = CloneFlags::CLONE_NEWUSER
+                    let pidfd = pidfd_open(init_pid)?;
+                    let mut flags = CloneFlags::CLONE_NEWUSER
                         | CloneFlags::CLONE_NEWNS
                         | CloneFlags::CLONE_NEWPID
                         | CloneFlags::CLONE_NEWNET
                         | CloneFlags::CLONE_NEWIPC
-                        | CloneFlags::CLONE_NEWUTS
-                        | CloneFlags::from_bits_retain(nix::libc::CLONE_NEWTIME);
+                        | CloneFlags::CLONE_NEWUTS;
+                    if container.time_namespace {
+                        flags |= CloneFlags::from_bits_retain(nix::libc::CLONE_NEWTIME);
+                    }
+                    if container.privileged {
+                        flags.remove(CloneFlags::CLONE_NEWUSER);
+                    }
                     nix::sched::setns(&pidfd, flags)
-                        .map_err(|v| format!("Cannot enter init namespaces: {v}"))?;
-                    let pipe = new_pipe()?;
+                        .map_err(ChildError::context("Cannot enter init namespaces"))?;
+                    join_root(container, init_pid)?;
                     let mut clone_args = CloneArgs::default();
                     clone_args.flag_parent();
                     clone_args.flag_into_cgroup(&cgroup_file);
+                    if clear_signal_handlers {
+                        clone_args.flag_clear_sighand();
+                    }
                     match unsafe { clone3(&clone_args) }? {
                         CloneResult::Child => {
                             let _ = catch_unwind(move || -> Result<Infallible, Error> {
+                                let tx = pipe.tx();
+                                set_child_panic_hook(Some(&tx));
                                 drop(cgroup_file);
                                 drop(pid_tx);
-                                let tx = pipe.tx();
-                                // Unlock parent process.
-                                write_result(
-                                    tx,
+                                // Resolve user inside container.
+                                let (uid, gid, work_dir) = match &user_name {
+                                    Some(name) => {
+                                        let (uid, gid, home) =
+                                            write_error(&tx, lookup_user(container, name))?;
+                                        (uid, gid, if home_work_dir { home } else { work_dir })
+                                    }
+                                    None => (uid, gid, work_dir),
+                                };
+                                // Setup default environment.
+                                let environ = if default_env {
+                                    write_error(&tx, default_environ(&environ))?
+                                } else {
+                                    environ
+                                };
+                                // Report setup error to parent process.
+                                write_error(
+                                    &tx,
                                     move || -> Result<(), Error> {
                                         // Setup cgroup namespace.
                                         nix::sched::setns(pidfd, CloneFlags::CLONE_NEWCGROUP)
-                                            .map_err(|v| {
-                                                format!("Cannot enter cgroup namespace: {v}")
-                                            })?;
+                                            .map_err(ChildError::context(
+                                                "Cannot enter cgroup namespace",
+                                            ))?;
+                                        // Setup private mounts.
+                                        if !scratch_dirs.is_empty() || resolv_conf.is_some() {
+                                            setup_private_mounts(
+                                                &scratch_dirs,
+                                                resolv_conf.as_deref(),
+                                            )
+                                            .map_err(
+                                                ChildError::context("Cannot setup private mounts"),
+                                            )?;
+                                        }
                                         // Setup stdio.
                                         dup2(
                                             stdin
@@ -411,39 +945,89 @@ impl ProcessOptions {
                                         )?;
                                         // Close file descriptors.
                                         close_exec_from(3)?;
+                                        // Setup umask.
+                                        if let Some(mask) = mask {
+                                            umask(mask);
+                                        }
+                                        // Setup scheduling.
+                                        if let Some(policy) = scheduler {
+                                            set_scheduler(policy)?;
+                                        }
+                                        if let Some(nice) = nice {
+                                            set_nice(nice)?;
+                                        }
+                                        // Setup CPU time limit.
+                                        if let Some(limit) = cpu_time_limit {
+                                            set_cpu_time_limit(limit)?;
+                                        }
+                                        // Setup resource limits.
+                                        for &(resource, soft, hard) in &rlimits {
+                                            setrlimit(resource, soft, hard).map_err(
+                                                ChildError::context(format_args!(
+                                                    "Cannot set {resource:?}"
+                                                )),
+                                            )?;
+                                        }
                                         // Setup workdir.
-                                        chdir(&work_dir).map_err(|v| {
-                                            format!("Cannot change work directory: {v}")
-                                        })?;
+                                        chdir(&work_dir).map_err(ChildError::context(
+                                            "Cannot change work directory",
+                                        ))?;
+                                        // Setup audit login uid.
+                                        if let Some(uid) = loginuid {
+                                            set_loginuid(uid).map_err(ChildError::context(
+                                                "Cannot set loginuid",
+                                            ))?;
+                                        }
+                                        // Bounding set can be changed only with CAP_SETPCAP.
+                                        if drop_capabilities {
+                                            drop_bounding_capabilities().map_err(
+                                                ChildError::context("Cannot drop capabilities"),
+                                            )?;
+                                        }
                                         // Setup user.
-                                        container.user_mapper.set_user(uid, gid)
+                                        container.user_mapper.set_user(uid, gid)?;
+                                        if drop_capabilities {
+                                            clear_capabilities().map_err(ChildError::context(
+                                                "Cannot drop capabilities",
+                                            ))?;
+                                        }
+                                        if no_new_privs {
+                                            Errno::result(unsafe {
+                                                nix::libc::prctl(
+                                                    nix::libc::PR_SET_NO_NEW_PRIVS,
+                                                    1,
+                                                    0,
+                                                    0,
+                                                    0,
+                                                )
+                                            })
+                                            .map_err(ChildError::context(
+                                                "Cannot set no_new_privs",
+                                            ))?;
+                                        }
+                                        // Setup landlock.
+                                        if let Some(ruleset) = &landlock {
+                                            ruleset.restrict_self()?;
+                                        }
+                                        Ok(())
                                     }(),
-                                )??;
-                                // Prepare exec arguments.
-                                let filename = CString::new(command[0].as_bytes())?;
-                                let argv = Result::<Vec<_>, _>::from_iter(
-                                    command.iter().map(|v| CString::new(v.as_bytes())),
-                                )?;
-                                let envp = Result::<Vec<_>, _>::from_iter(
-                                    environ.iter().map(|v| CString::new(v.as_bytes())),
                                 )?;
-                                // Run process.
-                                Ok(execvpe(&filename, &argv, &envp)?)
+                                // Run process, pipe is closed on successful exec.
+                                write_error(&tx, execute(&command, &environ))
                             });
                             unsafe { nix::libc::_exit(2) }
                         }
                         CloneResult::Parent { child } => {
-                            exit_child(move || -> Result<(), Error> {
+                            exit_child({
                                 // Close stdio descriptors.
                                 drop(stdin);
                                 drop(stdout);
                                 drop(stderr);
                                 drop(dev_null);
+                                drop(pipe);
                                 // Send child pid to parent process.
-                                write_pid(pid_tx, child)?;
-                                // Await child process is started.
-                                read_result(pipe.rx())?
-                            }())
+                                write_pid(&pid_tx, child)
+                            })
                         }
                     }
                 });
@@ -457,14 +1041,26 @@ impl ProcessOptions {
                 drop(stderr);
                 drop(dev_null);
                 // Setup pipes.
-                let rx = pid_pipe.rx();
+                let rx = pipe.rx();
                 // Read subchild pid.
-                let sibling = unsafe { OwnedPid::from_raw(read_pid(rx)?) };
-                // Wait for child exit.
+                let sibling = unsafe { OwnedPid::from_raw(read_pid(pid_pipe.rx())?) };
+                // Wait for child exit, it does not wait for subchild start.
                 child.wait_success()?;
+                // Await subchild start result.
+                read_exec_result(rx)??;
+                #[cfg(feature = "tracing")]
+                tracing::debug!(pid = sibling.as_raw().as_raw(), "process started");
+                container.emit(ContainerEventKind::ProcessSpawned {
+                    pid: sibling.as_raw(),
+                });
                 // Return process.
                 Ok(Process {
                     pid: sibling.into_raw(),
+                    cgroup: process_cgroup,
+                    output,
+                    started: Instant::now(),
+                    wall_time_limit,
+                    cpu_time_limit: cpu_time_limit.is_some(),
                 })
             }
         }
@@ -473,6 +1069,73 @@ impl ProcessOptions {
 
 pub struct Process {
     pid: Pid,
+    cgroup: Option<Cgroup>,
+    output: PipedOutput,
+    started: Instant,
+    wall_time_limit: Option<Duration>,
+    cpu_time_limit: bool,
+}
+
+/// Represents time limit exceeded by process.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TimeLimit {
+    /// Process was killed after exceeding wall-clock time limit.
+    Wall,
+    /// Process was terminated by `SIGXCPU` after exceeding CPU time limit.
+    Cpu,
+}
+
+/// Represents exit reason of process.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ProcessExit {
+    /// Process exited with specified code.
+    Exited(i32),
+    /// Process was killed by specified signal.
+    Signaled(Signal),
+    /// Process was killed by OOM killer.
+    OomKilled,
+    /// Process exceeded specified time limit.
+    TimedOut(TimeLimit),
+}
+
+/// Represents resource usage of reaped process.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Rusage {
+    /// Maximum resident set size in bytes.
+    ///
+    /// Kernel reports `ru_maxrss` in kilobytes on Linux, value is converted.
+    pub max_rss: usize,
+    /// Time spent in user mode.
+    pub user_time: Duration,
+    /// Time spent in kernel mode.
+    pub system_time: Duration,
+    /// Number of voluntary context switches.
+    pub voluntary_context_switches: u64,
+    /// Number of involuntary context switches.
+    pub involuntary_context_switches: u64,
+}
+
+impl From<nix::libc::rusage> for Rusage {
+    fn from(value: nix::libc::rusage) -> Self {
+        let timeval = |v: nix::libc::timeval| {
+            Duration::from_secs(v.tv_sec as u64) + Duration::from_micros(v.tv_usec as u64)
+        };
+        Self {
+            max_rss: value.ru_maxrss as usize * 1024,
+            user_time: timeval(value.ru_utime),
+            system_time: timeval(value.ru_stime),
+            voluntary_context_switches: value.ru_nvcsw as u64,
+            involuntary_context_switches: value.ru_nivcsw as u64,
+        }
+    }
+}
+
+/// Represents result of process started with `ProcessOptions::run`.
+#[derive(Clone, Debug)]
+pub struct CommandOutput {
+    pub status: WaitStatus,
+    pub stdout: Vec<u8>,
+    pub stderr: Vec<u8>,
 }
 
 impl Process {
@@ -484,7 +1147,659 @@ impl Process {
         Ok(waitpid(self.pid, Some(WaitPidFlag::__WALL))?)
     }
 
+    /// Waits for process exit and returns its resource usage.
+    ///
+    /// Resource usage includes descendants of process that were waited for.
+    pub fn wait_with_rusage(&mut self) -> Result<(WaitStatus, Rusage), Error> {
+        let mut status = 0;
+        let mut rusage = std::mem::MaybeUninit::<nix::libc::rusage>::zeroed();
+        let pid = Errno::result(unsafe {
+            nix::libc::wait4(
+                self.pid.as_raw(),
+                &mut status,
+                nix::libc::__WALL,
+                rusage.as_mut_ptr(),
+            )
+        })?;
+        let status = WaitStatus::from_raw(Pid::from_raw(pid), status)?;
+        Ok((status, unsafe { rusage.assume_init() }.into()))
+    }
+
+    /// Waits for process exit enforcing time limits.
+    ///
+    /// Process is killed if it exceeds wall-clock time limit. Returns exceeded
+    /// time limit if any. Note that process which ignores `SIGXCPU` is killed
+    /// by `SIGKILL` that cannot be distinguished from other kills.
+    pub fn wait_limited(&mut self) -> Result<(WaitStatus, Option<TimeLimit>), Error> {
+        let status = match self.wall_time_limit {
+            Some(limit) => {
+                let timeout = limit.saturating_sub(self.started.elapsed());
+                match self.wait_timeout(timeout)? {
+                    Some(v) => v,
+                    None => {
+                        match nix::sys::signal::kill(self.pid, Signal::SIGKILL) {
+                            Ok(()) | Err(Errno::ESRCH) => {}
+                            Err(v) => return Err(v.into()),
+                        }
+                        return Ok((self.wait()?, Some(TimeLimit::Wall)));
+                    }
+                }
+            }
+            None => self.wait()?,
+        };
+        let exceeded = match status {
+            WaitStatus::Signaled(_, Signal::SIGXCPU, _) if self.cpu_time_limit => {
+                Some(TimeLimit::Cpu)
+            }
+            _ => None,
+        };
+        Ok((status, exceeded))
+    }
+
+    /// Waits for process exit enforcing time limits and classifies exit reason.
+    ///
+    /// Process killed by `SIGKILL` is reported as OOM killed if OOM killer
+    /// was triggered in process cgroup, so process cgroup should be specified.
+    pub fn wait_exit(&mut self) -> Result<ProcessExit, Error> {
+        let (status, exceeded) = self.wait_limited()?;
+        if let Some(limit) = exceeded {
+            return Ok(ProcessExit::TimedOut(limit));
+        }
+        match status {
+            WaitStatus::Exited(_, code) => Ok(ProcessExit::Exited(code)),
+            WaitStatus::Signaled(_, Signal::SIGKILL, _) if self.is_oom_killed() => {
+                Ok(ProcessExit::OomKilled)
+            }
+            WaitStatus::Signaled(_, signal, _) => Ok(ProcessExit::Signaled(signal)),
+            _ => Err(format!("Unexpected status: {status:?}").into()),
+        }
+    }
+
+    fn is_oom_killed(&self) -> bool {
+        match &self.cgroup {
+            Some(v) => v.memory_events().is_ok_and(|v| v.oom_kill > 0),
+            None => false,
+        }
+    }
+
+    /// Waits for process exit at most for specified timeout.
+    ///
+    /// Returns `None` if process is still running after timeout.
+    pub fn wait_timeout(&mut self, timeout: Duration) -> Result<Option<WaitStatus>, Error> {
+        let pidfd = pidfd_open(self.pid)?;
+        let deadline = Instant::now() + timeout;
+        loop {
+            let timeout = deadline.saturating_duration_since(Instant::now());
+            let timeout = PollTimeout::try_from(timeout).unwrap_or(PollTimeout::MAX);
+            let mut fds = [PollFd::new(pidfd.as_fd(), PollFlags::POLLIN)];
+            match poll(&mut fds, timeout) {
+                Ok(0) => return Ok(None),
+                Ok(_) => return self.wait().map(Some),
+                Err(Errno::EINTR) => continue,
+                Err(err) => return Err(err.into()),
+            }
+        }
+    }
+
+    /// Drains piped stdout and stderr until EOF and waits for process exit.
+    ///
+    /// Requires output to be piped using `ProcessOptions::pipe_output`.
+    pub fn stream_output(
+        &mut self,
+        on_stdout: impl FnMut(&[u8]),
+        on_stderr: impl FnMut(&[u8]),
+    ) -> Result<WaitStatus, Error> {
+        let (stdout, stderr) = self.output.take().ok_or("Process output is not piped")?;
+        drain_output(stdout, stderr, on_stdout, on_stderr)?;
+        self.wait()
+    }
+
+    /// Returns dedicated cgroup of process.
+    pub fn cgroup(&self) -> Option<&Cgroup> {
+        self.cgroup.as_ref()
+    }
+
+    /// Returns current memory usage of process cgroup.
+    pub fn memory_current(&self) -> Result<usize, Error> {
+        self.require_cgroup()?.memory_current()
+    }
+
+    /// Returns peak memory usage of process cgroup.
+    pub fn memory_peak(&self) -> Result<usize, Error> {
+        self.require_cgroup()?.memory_peak()
+    }
+
+    /// Returns CPU usage of process cgroup.
+    pub fn cpu_usage(&self) -> Result<CgroupCpuUsage, Error> {
+        self.require_cgroup()?.cpu_usage()
+    }
+
     pub fn options() -> ProcessOptions {
         ProcessOptions::new()
     }
+
+    fn require_cgroup(&self) -> Result<&Cgroup, Error> {
+        self.cgroup
+            .as_ref()
+            .ok_or_else(|| "Process is started without dedicated cgroup".into())
+    }
+}
+
+/// Represents scheduling policy for process.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SchedPolicy {
+    /// Default time-sharing policy (`SCHED_OTHER`).
+    Other,
+    /// Policy for CPU-intensive non-interactive processes (`SCHED_BATCH`).
+    Batch,
+    /// Policy for very low priority background processes (`SCHED_IDLE`).
+    Idle,
+}
+
+/// Writes error to parent process and returns it.
+fn write_error<T>(tx: &File, result: Result<T, Error>) -> Result<T, Error> {
+    match result {
+        Ok(v) => Ok(v),
+        Err(err) => match write_result(tx, Err(err))? {
+            Ok(()) => unreachable!(),
+            Err(err) => Err(err),
+        },
+    }
+}
+
+/// Executes command and returns error if exec fails.
+fn execute(command: &[String], environ: &[String]) -> Result<Infallible, Error> {
+    let filename = CString::new(command[0].as_bytes())?;
+    let argv = Result::<Vec<_>, _>::from_iter(command.iter().map(|v| CString::new(v.as_bytes())))?;
+    let envp = Result::<Vec<_>, _>::from_iter(environ.iter().map(|v| CString::new(v.as_bytes())))?;
+    match execvpe(&filename, &argv, &envp) {
+        Ok(v) => match v {},
+        Err(Errno::ENOENT) => Err(ChildError::new(
+            Some(Errno::ENOENT),
+            format!("Executable {:?} not found in rootfs", command[0]),
+        )
+        .into()),
+        Err(v) => {
+            Err(ChildError::context(format_args!("Cannot execute {:?}", command[0]))(v).into())
+        }
+    }
+}
+
+/// Returns uid, gid and home directory of user from `/etc/passwd`.
+fn lookup_user(container: &Container, name: &str) -> Result<(Uid, Gid, PathBuf), Error> {
+    let content = std::fs::read_to_string("/etc/passwd")
+        .map_err(ChildError::context("Cannot read /etc/passwd"))?;
+    let entry = content
+        .lines()
+        .map(|v| v.split(':').collect::<Vec<_>>())
+        .find(|v| v.len() >= 7 && v[0] == name)
+        .ok_or_else(|| format!("User {name:?} does not exist in container"))?;
+    let uid = Uid::from(entry[2].parse::<u32>()?);
+    let gid = Gid::from(entry[3].parse::<u32>()?);
+    if !container.user_mapper.is_uid_mapped(uid) {
+        return Err(format!("User {} is not mapped", uid).into());
+    }
+    if !container.user_mapper.is_gid_mapped(gid) {
+        return Err(format!("Group {} is not mapped", gid).into());
+    }
+    let home = match entry[5] {
+        "" => "/",
+        v => v,
+    };
+    Ok((uid, gid, home.into()))
+}
+
+const DEFAULT_PATH: &str = "PATH=/usr/local/sbin:/usr/local/bin:/usr/sbin:/usr/bin:/sbin:/bin";
+
+/// Returns environment seeded with `/etc/environment` and default `PATH`.
+///
+/// Variables from specified environment override defaults.
+fn default_environ(environ: &[String]) -> Result<Vec<String>, Error> {
+    let content = match std::fs::read_to_string("/etc/environment") {
+        Ok(v) => v,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => String::new(),
+        Err(err) => return Err(ChildError::context("Cannot read /etc/environment")(err).into()),
+    };
+    let mut defaults = vec![DEFAULT_PATH.to_owned()];
+    for line in content.lines().map(str::trim) {
+        let line = line.strip_prefix("export ").unwrap_or(line);
+        let Some((key, value)) = line.split_once('=') else {
+            continue;
+        };
+        if key.is_empty() || key.starts_with('#') {
+            continue;
+        }
+        let value = match value.as_bytes() {
+            [b'"', .., b'"'] | [b'\'', .., b'\''] => &value[1..value.len() - 1],
+            _ => value,
+        };
+        defaults.push(format!("{key}={value}"));
+    }
+    let mut result = Vec::new();
+    for entry in defaults.into_iter().chain(environ.iter().cloned()) {
+        let key = entry.split_once('=').map_or(entry.as_str(), |v| v.0);
+        result.retain(|v: &String| v.split_once('=').map_or(v.as_str(), |v| v.0) != key);
+        result.push(entry);
+    }
+    Ok(result)
+}
+
+/// Clones process in user namespace and specified namespaces of init process.
+///
+/// Namespaces are joined by intermediate process, that reports pid of clone
+/// and exits. Clone is created with `CLONE_PARENT`, so it is a child of
+/// current process. User namespace is not joined if `join_user` is false.
+unsafe fn clone_in_namespaces_of(
+    init_pid: Pid,
+    namespaces: Namespaces,
+    join_user: bool,
+    mut clone_args: CloneArgs,
+) -> Result<CloneResult, Error> {
+    let pipe = new_pipe()?;
+    match fork()? {
+        ForkResult::Child => {
+            set_child_panic_hook(None);
+            let tx = pipe.tx();
+            let result = (|| -> Result<CloneResult, Error> {
+                let pidfd = pidfd_open(init_pid)?;
+                let mut flags = namespaces.clone_flags();
+                if join_user {
+                    flags |= CloneFlags::CLONE_NEWUSER;
+                }
+                nix::sched::setns(&pidfd, flags)
+                    .map_err(ChildError::context("Cannot enter init namespaces"))?;
+                clone_args.flag_parent();
+                Ok(clone3(&clone_args)?)
+            })();
+            match result {
+                Ok(CloneResult::Child) => {
+                    drop(tx);
+                    Ok(CloneResult::Child)
+                }
+                Ok(CloneResult::Parent { child }) => {
+                    let _ = write_result(&tx, Ok(()));
+                    let _ = write_pid(&tx, child);
+                    nix::libc::_exit(0)
+                }
+                Err(err) => {
+                    let _ = write_result(&tx, Err(err));
+                    nix::libc::_exit(1)
+                }
+            }
+        }
+        ForkResult::Parent { child } => {
+            let child = OwnedPid::from_raw(child);
+            let rx = pipe.rx();
+            read_result(&rx)??;
+            let pid = read_pid(&rx)?;
+            child.wait_success()?;
+            Ok(CloneResult::Parent { child: pid })
+        }
+    }
+}
+
+/// Runs command as child of current process and reaps zombies until it exits.
+///
+/// Forwarded signals are blocked and received synchronously, so loop does not
+/// allocate. Command exit status is used as exit status of current process.
+fn run_reaper(
+    tx: File,
+    command: &[String],
+    environ: &[String],
+    signals: &[Signal],
+) -> Result<Infallible, Error> {
+    let mut mask = SigSet::empty();
+    mask.add(Signal::SIGCHLD);
+    for signal in signals {
+        mask.add(*signal);
+    }
+    let mut old_mask = SigSet::empty();
+    sigprocmask(SigmaskHow::SIG_BLOCK, Some(&mask), Some(&mut old_mask))?;
+    let child = match unsafe { fork() }? {
+        ForkResult::Child => {
+            sigprocmask(SigmaskHow::SIG_SETMASK, Some(&old_mask), None)?;
+            return write_error(&tx, execute(command, environ));
+        }
+        ForkResult::Parent { child } => child,
+    };
+    // Pipe is closed when command is executed.
+    set_child_panic_hook(None);
+    drop(tx);
+    loop {
+        let signal = mask.wait()?;
+        if signal != Signal::SIGCHLD {
+            let _ = nix::sys::signal::kill(child, signal);
+            continue;
+        }
+        loop {
+            match waitpid(None, Some(WaitPidFlag::WNOHANG | WaitPidFlag::__WALL)) {
+                Ok(WaitStatus::StillAlive) | Err(Errno::ECHILD) => break,
+                Ok(WaitStatus::Exited(pid, code)) if pid == child => unsafe {
+                    nix::libc::_exit(code)
+                },
+                Ok(WaitStatus::Signaled(pid, signal, _)) if pid == child => unsafe {
+                    nix::libc::_exit(128 + signal as i32)
+                },
+                Ok(_) | Err(Errno::EINTR) => continue,
+                Err(err) => return Err(err.into()),
+            }
+        }
+    }
+}
+
+/// Allocates terminal and returns master, slave and path of slave.
+fn open_console() -> Result<(File, OwnedFd, PathBuf), Error> {
+    let master = nix::pty::posix_openpt(OFlag::O_RDWR | OFlag::O_NOCTTY | OFlag::O_CLOEXEC)?;
+    nix::pty::grantpt(&master)?;
+    nix::pty::unlockpt(&master)?;
+    let path = PathBuf::from(nix::pty::ptsname_r(&master)?);
+    let slave = nix::fcntl::open(
+        &path,
+        OFlag::O_RDWR | OFlag::O_NOCTTY | OFlag::O_CLOEXEC,
+        Mode::empty(),
+    )?;
+    let master = unsafe { File::from_raw_fd(master.into_raw_fd()) };
+    Ok((master, unsafe { OwnedFd::from_raw_fd(slave) }, path))
+}
+
+/// Makes terminal controlling terminal of new session and redirects stdio to it.
+fn setup_controlling_terminal(slave: &OwnedFd) -> Result<(), Error> {
+    nix::unistd::setsid()?;
+    Errno::result(unsafe { nix::libc::ioctl(slave.as_raw_fd(), nix::libc::TIOCSCTTY, 0) })?;
+    for fd in 0..3 {
+        dup2(slave.as_raw_fd(), fd)?;
+    }
+    Ok(())
+}
+
+/// Writes audit login uid of current process ignoring missing permissions.
+fn set_loginuid(uid: Uid) -> Result<(), Error> {
+    match std::fs::write("/proc/self/loginuid", uid.to_string()) {
+        Err(v) if v.raw_os_error() == Some(nix::libc::EPERM) => Ok(()),
+        v => Ok(v?),
+    }
+}
+
+/// Mounts private tmpfs directories and resolv.conf in new mount namespace.
+fn setup_private_mounts(
+    scratch_dirs: &[(PathBuf, usize)],
+    resolv_conf: Option<&Path>,
+) -> Result<(), Error> {
+    nix::sched::unshare(CloneFlags::CLONE_NEWNS)?;
+    nix::mount::mount(
+        None::<&str>,
+        "/",
+        None::<&str>,
+        nix::mount::MsFlags::MS_SLAVE | nix::mount::MsFlags::MS_REC,
+        None::<&str>,
+    )?;
+    for (path, size) in scratch_dirs {
+        std::fs::create_dir_all(path)?;
+        nix::mount::mount(
+            Some("tmpfs"),
+            path,
+            Some("tmpfs"),
+            nix::mount::MsFlags::MS_NOSUID | nix::mount::MsFlags::MS_NODEV,
+            Some(format!("size={size}").as_str()),
+        )
+        .map_err(ChildError::context(format_args!(
+            "Cannot mount scratch directory {path:?}"
+        )))?;
+    }
+    if let Some(path) = resolv_conf {
+        nix::mount::mount(
+            Some(path),
+            "/etc/resolv.conf",
+            None::<&str>,
+            nix::mount::MsFlags::MS_BIND,
+            None::<&str>,
+        )
+        .map_err(ChildError::context(format_args!(
+            "Cannot mount {path:?} over /etc/resolv.conf"
+        )))?;
+    }
+    Ok(())
+}
+
+fn set_cpu_time_limit(limit: Duration) -> Result<(), Error> {
+    let secs = limit.as_secs() + u64::from(limit.subsec_nanos() > 0);
+    let rlimit = nix::libc::rlimit {
+        rlim_cur: secs,
+        rlim_max: secs + 1,
+    };
+    Errno::result(unsafe { nix::libc::setrlimit(nix::libc::RLIMIT_CPU, &rlimit) })
+        .map_err(|v| format!("Cannot set CPU time limit: {v}"))?;
+    Ok(())
+}
+
+/// Drops all capabilities from bounding and ambient sets.
+fn drop_bounding_capabilities() -> Result<(), Error> {
+    let last_cap: nix::libc::c_ulong = std::fs::read_to_string("/proc/sys/kernel/cap_last_cap")?
+        .trim()
+        .parse()?;
+    for cap in 0..=last_cap {
+        Errno::result(unsafe { nix::libc::prctl(nix::libc::PR_CAPBSET_DROP, cap, 0, 0, 0) })?;
+    }
+    Errno::result(unsafe {
+        nix::libc::prctl(
+            nix::libc::PR_CAP_AMBIENT,
+            nix::libc::PR_CAP_AMBIENT_CLEAR_ALL,
+            0,
+            0,
+            0,
+        )
+    })?;
+    Ok(())
+}
+
+/// Clears effective, permitted and inheritable capabilities.
+fn clear_capabilities() -> Result<(), Error> {
+    #[repr(C)]
+    struct CapUserHeader {
+        version: u32,
+        pid: i32,
+    }
+    #[repr(C)]
+    #[derive(Clone, Copy, Default)]
+    struct CapUserData {
+        effective: u32,
+        permitted: u32,
+        inheritable: u32,
+    }
+    const LINUX_CAPABILITY_VERSION_3: u32 = 0x20080522;
+    let header = CapUserHeader {
+        version: LINUX_CAPABILITY_VERSION_3,
+        pid: 0,
+    };
+    let data = [CapUserData::default(); 2];
+    Errno::result(unsafe {
+        nix::libc::syscall(
+            nix::libc::SYS_capset,
+            &header as *const CapUserHeader,
+            data.as_ptr(),
+        )
+    })?;
+    Ok(())
+}
+
+fn set_scheduler(policy: SchedPolicy) -> Result<(), Error> {
+    let policy = match policy {
+        SchedPolicy::Other => nix::libc::SCHED_OTHER,
+        SchedPolicy::Batch => nix::libc::SCHED_BATCH,
+        SchedPolicy::Idle => nix::libc::SCHED_IDLE,
+    };
+    let param = nix::libc::sched_param { sched_priority: 0 };
+    Errno::result(unsafe { nix::libc::sched_setscheduler(0, policy, &param) })
+        .map_err(|v| format!("Cannot set scheduling policy: {v}"))?;
+    Ok(())
+}
+
+fn set_nice(nice: i32) -> Result<(), Error> {
+    match Errno::result(unsafe { nix::libc::setpriority(nix::libc::PRIO_PROCESS, 0, nice) }) {
+        Ok(_) => Ok(()),
+        Err(Errno::EPERM | Errno::EACCES) => Err(format!(
+            "Cannot set nice value {nice}: raising priority requires CAP_SYS_NICE"
+        )
+        .into()),
+        Err(v) => Err(format!("Cannot set nice value {nice}: {v}").into()),
+    }
+}
+
+fn set_sysctl(key: &str, value: &str) -> Result<(), Error> {
+    let path = key.replace('.', "/");
+    if path
+        .split('/')
+        .any(|v| v.is_empty() || v == "." || v == "..")
+    {
+        return Err(format!("Invalid sysctl {key:?}").into());
+    }
+    let path = Path::new("/proc/sys").join(path);
+    match std::fs::write(&path, value) {
+        Ok(()) => Ok(()),
+        Err(v) if matches!(v.raw_os_error(), Some(nix::libc::EPERM | nix::libc::EACCES)) => {
+            Err(ChildError::new(
+                v.raw_os_error().map(Errno::from_raw),
+                format!("Cannot set sysctl {key}: permission denied, sysctl is not namespaced"),
+            )
+            .into())
+        }
+        Err(v) => Err(ChildError::context(format_args!("Cannot set sysctl {key}"))(v).into()),
+    }
+}
+
+/// Verifies that process can be started in cgroup.
+///
+/// Process started in frozen cgroup is frozen immediately and cannot finish
+/// synchronization with parent, which blocks parent forever.
+fn check_not_frozen(cgroup: &Cgroup) -> Result<(), Error> {
+    if cgroup.is_frozen()? {
+        return Err(format!(
+            "Cannot start process in frozen cgroup {:?}, thaw it first",
+            cgroup.as_path()
+        )
+        .into());
+    }
+    Ok(())
+}
+
+/// Creates process cgroup with applied limits.
+///
+/// Limits should be applied before process is cloned into cgroup, otherwise
+/// process can run unconstrained for some time.
+fn create_process_cgroup(
+    container: &Container,
+    name: PathBuf,
+    limits: &ResourceLimits,
+) -> Result<Option<Cgroup>, Error> {
+    if name.is_empty() {
+        if !limits.is_empty() {
+            return Err("Process limits require process cgroup".into());
+        }
+        return Ok(None);
+    }
+    let cgroup = container.cgroup.child(name)?;
+    // Enable delegated controllers for process cgroups on a best-effort basis,
+    // so limits of process cgroups can be applied. Controllers cannot be enabled
+    // while container cgroup has processes, for example init process without
+    // own cgroup.
+    if container.cgroup.processes()?.is_empty() {
+        let enabled = container.cgroup.subtree_controller_set()?;
+        let delegated = container.cgroup.controller_set()?.difference(&enabled);
+        if !delegated.is_empty() {
+            let _ = container.cgroup.add_subtree_controllers(delegated.into());
+        }
+    }
+    cgroup.create()?;
+    cgroup
+        .apply(limits)
+        .map_err(|v| format!("Cannot apply process limits: {v}"))?;
+    Ok(Some(cgroup))
+}
+
+/// Represents read ends of stdout and stderr pipes.
+type PipedOutput = Option<(File, File)>;
+
+/// Creates pipes for stdout and stderr if output should be piped.
+fn output_pipes(
+    pipe_output: bool,
+    stdout: Option<OwnedFd>,
+    stderr: Option<OwnedFd>,
+) -> Result<(Option<OwnedFd>, Option<OwnedFd>, PipedOutput), Error> {
+    if !pipe_output {
+        return Ok((stdout, stderr, None));
+    }
+    if stdout.is_some() || stderr.is_some() {
+        return Err("Cannot pipe output with custom stdout or stderr".into());
+    }
+    let (stdout_rx, stdout_tx) = nix::unistd::pipe2(OFlag::O_CLOEXEC)?;
+    let (stderr_rx, stderr_tx) = nix::unistd::pipe2(OFlag::O_CLOEXEC)?;
+    Ok((
+        Some(stdout_tx),
+        Some(stderr_tx),
+        Some((File::from(stdout_rx), File::from(stderr_rx))),
+    ))
+}
+
+/// Duplicates file descriptor of current process if stream is not specified.
+fn inherit_fd(fd: Option<OwnedFd>, inherited: BorrowedFd) -> Result<Option<OwnedFd>, Error> {
+    match fd {
+        Some(v) => Ok(Some(v)),
+        None => Ok(Some(inherited.try_clone_to_owned()?)),
+    }
+}
+
+fn drain_output(
+    stdout: File,
+    stderr: File,
+    mut on_stdout: impl FnMut(&[u8]),
+    mut on_stderr: impl FnMut(&[u8]),
+) -> Result<(), Error> {
+    let mut stdout = Some(stdout);
+    let mut stderr = Some(stderr);
+    let mut buf = vec![0; 64 * 1024];
+    while stdout.is_some() || stderr.is_some() {
+        let (stdout_ready, stderr_ready) = {
+            let mut fds = Vec::with_capacity(2);
+            if let Some(v) = &stdout {
+                fds.push(PollFd::new(v.as_fd(), PollFlags::POLLIN));
+            }
+            if let Some(v) = &stderr {
+                fds.push(PollFd::new(v.as_fd(), PollFlags::POLLIN));
+            }
+            match poll(&mut fds, PollTimeout::NONE) {
+                Ok(_) => {}
+                Err(Errno::EINTR) => continue,
+                Err(err) => return Err(err.into()),
+            }
+            let mut ready = fds
+                .iter()
+                .map(|v| v.revents().is_some_and(|v| !v.is_empty()));
+            let stdout_ready = stdout.is_some() && ready.next().unwrap_or(false);
+            let stderr_ready = stderr.is_some() && ready.next().unwrap_or(false);
+            (stdout_ready, stderr_ready)
+        };
+        if stdout_ready {
+            read_output(&mut stdout, &mut buf, &mut on_stdout)?;
+        }
+        if stderr_ready {
+            read_output(&mut stderr, &mut buf, &mut on_stderr)?;
+        }
+    }
+    Ok(())
+}
+
+fn read_output(
+    file: &mut Option<File>,
+    buf: &mut [u8],
+    callback: &mut impl FnMut(&[u8]),
+) -> Result<(), Error> {
+    if let Some(reader) = file {
+        match reader.read(buf) {
+            Ok(0) => *file = None,
+            Ok(len) => callback(&buf[..len]),
+            Err(err) if err.kind() == std::io::ErrorKind::Interrupted => {}
+            Err(err) => return Err(err.into()),
+        }
+    }
+    Ok(())
 }
@@ -1,6 +1,11 @@
+use std::ffi::CString;
+use std::fmt::{Display, Formatter};
 use std::fs::File;
 use std::io::{Read, Write};
-use std::os::fd::{AsRawFd, FromRawFd, RawFd};
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, AtomicI32, Ordering};
 
 use nix::errno::Errno;
 use nix::libc::{c_int, c_uint, close_range, syscall};
@@ -10,6 +15,9 @@ use crate::Error;
 
 pub type Pid = nix::unistd::Pid;
 
+const CLONE_CLEAR_SIGHAND: u64 = 0x100000000;
+const CLONE_INTO_CGROUP: u64 = 0x200000000;
+
 #[repr(C, align(8))]
 #[derive(Debug, Default)]
 pub(crate) struct CloneArgs {
@@ -63,9 +71,13 @@ impl CloneArgs {
         self.flags |= nix::libc::CLONE_NEWCGROUP as u64;
     }
 
+    /// Resets handled signals to default dispositions in child (Linux 5.5+).
+    pub fn flag_clear_sighand(&mut self) {
+        self.flags |= CLONE_CLEAR_SIGHAND;
+    }
+
     pub fn flag_into_cgroup<T: AsRawFd>(&mut self, cgroup: &T) {
-        // self.flags |= nix::libc::CLONE_INTO_CGROUP as u64;
-        self.flags |= 0x200000000;
+        self.flags |= CLONE_INTO_CGROUP;
         self.cgroup = cgroup.as_raw_fd() as u64;
     }
 }
@@ -75,18 +87,76 @@ pub(crate) enum CloneResult {
     Parent { child: Pid },
 }
 
+/// Set when kernel does not support clone3 (Linux < 5.3).
+static CLONE3_UNSUPPORTED: AtomicBool = AtomicBool::new(false);
+
 pub(crate) unsafe fn clone3(cl_args: &CloneArgs) -> Result<CloneResult, Errno> {
+    if !CLONE3_UNSUPPORTED.load(Ordering::Relaxed) {
+        let res = syscall(
+            nix::libc::SYS_clone3,
+            cl_args as *const CloneArgs,
+            core::mem::size_of::<CloneArgs>(),
+        );
+        match Errno::result(res) {
+            Err(Errno::ENOSYS) => CLONE3_UNSUPPORTED.store(true, Ordering::Relaxed),
+            res => return res.map(into_clone_result),
+        }
+    }
+    clone_fallback(cl_args)
+}
+
+/// Emulates clone3 using legacy clone syscall.
+///
+/// Kernels without clone3 do not support time namespaces, so `CLONE_NEWTIME`
+/// is ignored. `CLONE_CLEAR_SIGHAND` is also ignored, since handlers are reset
+/// on exec anyway. `CLONE_INTO_CGROUP` is emulated by moving child to cgroup from
+/// parent process, so child can run outside of cgroup for a short time.
+unsafe fn clone_fallback(cl_args: &CloneArgs) -> Result<CloneResult, Errno> {
+    let flags = cl_args.flags
+        & !(CLONE_INTO_CGROUP | CLONE_CLEAR_SIGHAND | nix::libc::CLONE_NEWTIME as u64);
+    if flags > u32::MAX as u64 || cl_args.exit_signal > 0xff {
+        return Err(Errno::EINVAL);
+    }
     let res = syscall(
-        nix::libc::SYS_clone3,
-        cl_args as *const CloneArgs,
-        core::mem::size_of::<CloneArgs>(),
+        nix::libc::SYS_clone,
+        flags | cl_args.exit_signal,
+        0 as nix::libc::c_ulong,
+        0 as nix::libc::c_ulong,
+        0 as nix::libc::c_ulong,
+        0 as nix::libc::c_ulong,
     );
-    Errno::result(res).map(|v| match v {
+    let result = Errno::result(res).map(into_clone_result)?;
+    if let CloneResult::Parent { child } = &result {
+        if cl_args.flags & CLONE_INTO_CGROUP != 0 {
+            if let Err(err) = move_into_cgroup(cl_args.cgroup as RawFd, *child) {
+                let _ = nix::sys::signal::kill(*child, nix::sys::signal::Signal::SIGKILL);
+                let _ = waitpid(*child, Some(WaitPidFlag::__WALL));
+                return Err(err);
+            }
+        }
+    }
+    Ok(result)
+}
+
+fn into_clone_result(res: nix::libc::c_long) -> CloneResult {
+    match res {
         0 => CloneResult::Child,
         v => CloneResult::Parent {
             child: Pid::from_raw(v as nix::libc::pid_t),
         },
-    })
+    }
+}
+
+fn move_into_cgroup(cgroup: RawFd, pid: Pid) -> Result<(), Errno> {
+    let fd = nix::fcntl::openat(
+        Some(cgroup),
+        "cgroup.procs",
+        nix::fcntl::OFlag::O_WRONLY | nix::fcntl::OFlag::O_CLOEXEC,
+        nix::sys::stat::Mode::empty(),
+    )?;
+    let mut file = unsafe { File::from_raw_fd(fd) };
+    file.write_all(pid.to_string().as_bytes())
+        .map_err(|v| Errno::from_raw(v.raw_os_error().unwrap_or(nix::libc::EIO)))
 }
 
 pub(crate) fn pidfd_open(pid: Pid) -> Result<File, Errno> {
@@ -94,6 +164,65 @@ pub(crate) fn pidfd_open(pid: Pid) -> Result<File, Errno> {
     Errno::result(res).map(|v| unsafe { File::from_raw_fd(v as RawFd) })
 }
 
+pub(crate) const MOUNT_ATTR_RDONLY: u64 = 0x00000001;
+pub(crate) const MOUNT_ATTR_IDMAP: u64 = 0x00100000;
+
+#[repr(C)]
+#[derive(Debug, Default)]
+pub(crate) struct MountAttr {
+    pub attr_set: u64,
+    pub attr_clr: u64,
+    pub propagation: u64,
+    pub userns_fd: u64,
+}
+
+pub(crate) fn open_tree(path: &Path, flags: c_uint) -> Result<OwnedFd, Errno> {
+    let path = CString::new(path.as_os_str().as_bytes()).map_err(|_| Errno::EINVAL)?;
+    let res = unsafe {
+        syscall(
+            nix::libc::SYS_open_tree,
+            nix::libc::AT_FDCWD,
+            path.as_ptr(),
+            flags,
+        )
+    };
+    Errno::result(res).map(|v| unsafe { OwnedFd::from_raw_fd(v as RawFd) })
+}
+
+pub(crate) fn mount_setattr<T: AsRawFd>(
+    fd: &T,
+    flags: c_uint,
+    attr: &MountAttr,
+) -> Result<(), Errno> {
+    let res = unsafe {
+        syscall(
+            nix::libc::SYS_mount_setattr,
+            fd.as_raw_fd(),
+            c"".as_ptr(),
+            flags | nix::libc::AT_EMPTY_PATH as c_uint,
+            attr as *const MountAttr,
+            core::mem::size_of::<MountAttr>(),
+        )
+    };
+    Errno::result(res).map(|_| ())
+}
+
+pub(crate) fn move_mount<T: AsRawFd>(fd: &T, target: &Path) -> Result<(), Errno> {
+    const MOVE_MOUNT_F_EMPTY_PATH: c_uint = 0x00000004;
+    let target = CString::new(target.as_os_str().as_bytes()).map_err(|_| Errno::EINVAL)?;
+    let res = unsafe {
+        syscall(
+            nix::libc::SYS_move_mount,
+            fd.as_raw_fd(),
+            c"".as_ptr(),
+            nix::libc::AT_FDCWD,
+            target.as_ptr(),
+            MOVE_MOUNT_F_EMPTY_PATH,
+        )
+    };
+    Errno::result(res).map(|_| ())
+}
+
 pub(crate) fn close_exec_from(fd: c_uint) -> Result<(), Errno> {
     let res = unsafe { close_range(fd, c_uint::MAX, nix::libc::CLOSE_RANGE_CLOEXEC as c_int) };
     Errno::result(res).map(|_| ())
@@ -105,19 +234,21 @@ pub(crate) struct Pipe {
 }
 
 impl Pipe {
-    pub fn rx(self) -> impl Read {
+    pub fn rx(self) -> File {
         drop(self.tx);
         self.rx
     }
 
-    pub fn tx(self) -> impl Write {
+    pub fn tx(self) -> File {
         drop(self.rx);
         self.tx
     }
 }
 
+/// Creates pipe with `O_CLOEXEC`, so processes spawned by other threads do
+/// not inherit it and successful exec of child closes it.
 pub(crate) fn new_pipe() -> Result<Pipe, Error> {
-    let (rx, tx) = nix::unistd::pipe()?;
+    let (rx, tx) = nix::unistd::pipe2(nix::fcntl::OFlag::O_CLOEXEC)?;
     let rx = File::from(rx);
     let tx = File::from(tx);
     Ok(Pipe { rx, tx })
@@ -129,14 +260,36 @@ pub(super) fn read_result(mut rx: impl Read) -> Result<Result<(), Error>, Error>
     match u8::from_le_bytes(buf) {
         0 => Ok(Ok(())),
         1 => {
+            let mut buf = [0; std::mem::size_of::<i32>()];
+            rx.read_exact(&mut buf)?;
+            let errno = match i32::from_le_bytes(buf) {
+                0 => None,
+                v => Some(Errno::from_raw(v)),
+            };
             let mut buf = [0; std::mem::size_of::<usize>()];
             rx.read_exact(&mut buf)?;
             let len = usize::from_le_bytes(buf);
             let mut buf = vec![0; len];
             rx.read_exact(&mut buf)?;
-            Ok(Err(String::from_utf8(buf)?.into()))
+            Ok(Err(ChildError::new(errno, String::from_utf8(buf)?).into()))
+        }
+        CHILD_PANIC_MARKER => Ok(Err(ChildError::new(None, "Child process panicked").into())),
+        v => Err(format!("Invalid child result marker: {v}").into()),
+    }
+}
+
+/// Reads result of child process that executes program.
+///
+/// Pipe is closed on successful exec without any data, so EOF means success.
+pub(super) fn read_exec_result(mut rx: impl Read) -> Result<Result<(), Error>, Error> {
+    let mut buf = [0; 1];
+    loop {
+        match rx.read(&mut buf) {
+            Ok(0) => return Ok(Ok(())),
+            Ok(_) => return read_result(buf.chain(rx)),
+            Err(v) if v.kind() == std::io::ErrorKind::Interrupted => continue,
+            Err(v) => return Err(v.into()),
         }
-        _ => unreachable!(),
     }
 }
 
@@ -151,8 +304,10 @@ pub(super) fn write_result(
         }
         Err(err) => {
             tx.write_all(&u8::to_le_bytes(1))?;
+            let errno = error_errno(err.as_ref()).map_or(0, |v| v as i32);
+            tx.write_all(&i32::to_le_bytes(errno))?;
             let msg = err.to_string();
-            tx.write_all(&usize::to_le_bytes(msg.as_bytes().len()))?;
+            tx.write_all(&usize::to_le_bytes(msg.len()))?;
             tx.write_all(msg.as_bytes())?;
             Ok(Err(err))
         }
@@ -179,6 +334,96 @@ pub(super) fn write_pid(mut tx: impl Write, pid: Pid) -> Result<(), Error> {
     Ok(())
 }
 
+/// Represents error reported by child process over result pipe.
+///
+/// Error keeps errno of original error if child reported it, so errors like
+/// mount `EPERM` and chdir `ENOENT` can be distinguished by parent.
+#[derive(Clone, Debug)]
+pub struct ChildError {
+    errno: Option<Errno>,
+    message: String,
+}
+
+impl ChildError {
+    pub fn new(errno: Option<Errno>, message: impl Into<String>) -> Self {
+        Self {
+            errno,
+            message: message.into(),
+        }
+    }
+
+    /// Returns errno of original error in child process.
+    pub fn errno(&self) -> Option<Errno> {
+        self.errno
+    }
+
+    pub fn message(&self) -> &str {
+        &self.message
+    }
+
+    /// Returns function that prefixes error with context keeping errno.
+    pub(crate) fn context<E: Into<Error>>(context: impl Display) -> impl FnOnce(E) -> Self {
+        move |err| {
+            let err = err.into();
+            Self::new(error_errno(err.as_ref()), format!("{context}: {err}"))
+        }
+    }
+}
+
+impl Display for ChildError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+impl std::error::Error for ChildError {}
+
+/// Finds errno of error or its sources.
+fn error_errno(err: &(dyn std::error::Error + 'static)) -> Option<Errno> {
+    let mut err = Some(err);
+    while let Some(v) = err {
+        if let Some(v) = v.downcast_ref::<ChildError>() {
+            return v.errno;
+        }
+        if let Some(v) = v.downcast_ref::<Errno>() {
+            return Some(*v);
+        }
+        if let Some(v) = v
+            .downcast_ref::<std::io::Error>()
+            .and_then(|v| v.raw_os_error())
+        {
+            return Some(Errno::from_raw(v));
+        }
+        err = v.source();
+    }
+    None
+}
+
+/// Marker written to result pipe by panicked child process.
+const CHILD_PANIC_MARKER: u8 = 2;
+
+/// Result pipe descriptor of current child process.
+static CHILD_PANIC_FD: AtomicI32 = AtomicI32::new(-1);
+
+/// Installs panic hook for child process created by fork or clone.
+///
+/// Default hook locks stderr and allocates, which can deadlock after fork.
+/// Installed hook only writes marker to result pipe (if any) and exits with
+/// code 3, previous hook is leaked to avoid deallocation.
+pub(super) fn set_child_panic_hook(tx: Option<&File>) {
+    CHILD_PANIC_FD.store(tx.map_or(-1, |v| v.as_raw_fd()), Ordering::Relaxed);
+    std::mem::forget(std::panic::take_hook());
+    // Hook has no captures, so boxing it does not allocate.
+    std::panic::set_hook(Box::new(|_| {
+        let fd = CHILD_PANIC_FD.load(Ordering::Relaxed);
+        if fd >= 0 {
+            let buf = [CHILD_PANIC_MARKER];
+            unsafe { nix::libc::write(fd, buf.as_ptr().cast(), buf.len()) };
+        }
+        unsafe { nix::libc::_exit(3) }
+    }));
+}
+
 pub(super) fn exit_child<T, E>(result: Result<T, E>) -> ! {
     match result {
         Ok(_) => unsafe { nix::libc::_exit(0) },
@@ -213,9 +458,13 @@ impl OwnedPid {
 }
 
 impl Drop for OwnedPid {
+    /// Reaps child process on a best-effort basis.
+    ///
+    /// Errors are ignored since drop can be called during unwinding, where
+    /// panic aborts whole program. For example, child can be already reaped.
     fn drop(&mut self) {
         if let Some(pid) = self.0.take() {
-            waitpid(pid, Some(WaitPidFlag::__WALL)).unwrap();
+            while let Err(Errno::EINTR) = waitpid(pid, Some(WaitPidFlag::__WALL)) {}
         }
     }
 }
@@ -235,3 +484,23 @@ pub(crate) fn ignore_kind(
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use nix::unistd::{fork, ForkResult};
+
+    use super::*;
+
+    #[test]
+    fn test_owned_pid_drop_reaped() {
+        let pid = match unsafe { fork() }.unwrap() {
+            ForkResult::Child => unsafe { nix::libc::_exit(0) },
+            ForkResult::Parent { child } => child,
+        };
+        let owned = unsafe { OwnedPid::from_raw(pid) };
+        let status = waitpid(owned.as_raw(), Some(WaitPidFlag::__WALL)).unwrap();
+        assert_eq!(status, WaitStatus::Exited(pid, 0));
+        // Child is already reaped, so drop gets ECHILD and must not panic.
+        drop(owned);
+    }
+}
@@ -0,0 +1,195 @@
+//! Helpers for integration tests of containers.
+//!
+//! Available with `test-utils` feature.
+
+use std::fs::File;
+use std::io::ErrorKind;
+use std::ops::Deref;
+use std::path::{Path, PathBuf};
+use std::sync::Once;
+
+use rand::distributions::{Alphanumeric, DistString as _};
+use tar::Archive;
+
+use nix::unistd::{Gid, Uid};
+
+use crate::{
+    run_as_root, BaseMounts, BinNewIdMapper, Cgroup, Container, ContainerOptions, Error, Mount,
+    OverlayMount,
+};
+
+/// Represents temporary directory that is removed on drop.
+pub struct TempDir(PathBuf);
+
+impl TempDir {
+    /// Creates directory with random name in system temporary directory.
+    pub fn new() -> Result<Self, Error> {
+        Self::new_in(std::env::temp_dir())
+    }
+
+    /// Creates directory with random name in specified directory.
+    pub fn new_in(tmpdir: impl AsRef<Path>) -> Result<Self, Error> {
+        let tmpdir = tmpdir.as_ref();
+        let path = loop {
+            let path = tmpdir.join(format!("test-{}", rand_string(32)));
+            match std::fs::metadata(&path) {
+                Ok(_) => continue,
+                Err(v) if v.kind() == ErrorKind::NotFound => break path,
+                Err(v) => return Err(v.into()),
+            }
+        };
+        std::fs::create_dir_all(&path)?;
+        Ok(Self(path))
+    }
+
+    pub fn join<P: AsRef<Path>>(&self, path: P) -> PathBuf {
+        self.0.join(path)
+    }
+
+    pub fn as_path(&self) -> &Path {
+        self.0.as_path()
+    }
+
+    pub fn remove(self) -> Result<(), Error> {
+        Ok(std::fs::remove_dir_all(&self.0)?)
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}
+
+/// Returns random alphanumeric string of specified length.
+pub fn rand_string(len: usize) -> String {
+    Alphanumeric.sample_string(&mut rand::thread_rng(), len)
+}
+
+/// Returns archive of busybox rootfs.
+///
+/// Archive is downloaded once to `tests` directory of current crate.
+pub fn get_rootfs() -> Result<Archive<File>, Error> {
+    static ONCE: Once = Once::new();
+    ONCE.call_once(|| {
+        if Path::new("./tests/rootfs.tar").exists() {
+            return;
+        }
+        std::fs::create_dir_all("./tests").unwrap();
+        assert!(std::process::Command::new("curl")
+            .arg("-fsSL")
+            .arg("--retry")
+            .arg("5")
+            .arg("https://github.com/docker-library/busybox/raw/31d342ad033e27c18723a516a2274ab39547be27/stable/glibc/busybox.tar.xz")
+            .arg("-o")
+            .arg("rootfs.tar.xz")
+            .current_dir("./tests")
+            .spawn()
+            .unwrap()
+            .wait()
+            .unwrap()
+            .success());
+        assert!(std::process::Command::new("xz")
+            .arg("-df")
+            .arg("rootfs.tar.xz")
+            .current_dir("./tests")
+            .spawn()
+            .unwrap()
+            .wait()
+            .unwrap()
+            .success());
+    });
+    let mut rootfs = Archive::new(File::open("./tests/rootfs.tar")?);
+    rootfs.set_preserve_permissions(true);
+    rootfs.set_preserve_ownerships(true);
+    rootfs.set_unpack_xattrs(true);
+    Ok(rootfs)
+}
+
+/// Returns cgroup for tests.
+///
+/// Cgroup is read from `TEST_CGROUP_PATH` or parent of current cgroup is used.
+pub fn get_cgroup() -> Result<Cgroup, Error> {
+    if let Ok(v) = std::env::var("TEST_CGROUP_PATH") {
+        let path = PathBuf::from(v);
+        let root_path = "/sys/fs/cgroup";
+        return Cgroup::new(root_path, path.strip_prefix(root_path).unwrap());
+    }
+    Ok(Cgroup::current()?
+        .parent()
+        .ok_or("Current process cannot be in root cgroup")?)
+}
+
+/// Represents temporary cgroup that is removed recursively on drop.
+pub struct TempCgroup(Cgroup);
+
+impl TempCgroup {
+    /// Creates child cgroup with random name of cgroup for tests.
+    pub fn new() -> Result<Self, Error> {
+        let cgroup = get_cgroup()?.child(format!("test-{}", rand_string(32)))?;
+        cgroup.create()?;
+        Ok(Self(cgroup))
+    }
+}
+
+impl Deref for TempCgroup {
+    type Target = Cgroup;
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+impl Drop for TempCgroup {
+    fn drop(&mut self) {
+        let _ = self.0.remove_recursive();
+    }
+}
+
+/// Returns options of container with overlay over busybox rootfs.
+///
+/// Container uses specified cgroup, subordinate IDs of current user and base
+/// mounts followed by extra mounts. Rootfs and state of container are stored
+/// in returned directory, so it should outlive container.
+pub fn test_container_options(
+    cgroup: Cgroup,
+    extra_mounts: Vec<Box<dyn Mount>>,
+) -> Result<(TempDir, ContainerOptions), Error> {
+    let tmpdir = TempDir::new()?;
+    let state_dir = tmpdir.join("state");
+    let rootfs_dir = tmpdir.join("rootfs");
+    let user_mapper = BinNewIdMapper::new_root_subid(Uid::current(), Gid::current())?;
+    {
+        let rootfs_dir = rootfs_dir.clone();
+        let mut rootfs = get_rootfs()?;
+        run_as_root(&user_mapper, move || Ok(rootfs.unpack(rootfs_dir)?))?;
+    }
+    std::fs::create_dir(&state_dir)?;
+    std::fs::create_dir(state_dir.join("upper"))?;
+    std::fs::create_dir(state_dir.join("work"))?;
+    let mut options = Container::options()
+        .cgroup(cgroup)
+        .add_mount(OverlayMount::new(
+            vec![rootfs_dir],
+            state_dir.join("upper"),
+            state_dir.join("work"),
+        ))
+        .add_mount(BaseMounts::new())
+        .rootfs(state_dir.join("rootfs"))
+        .user_mapper(user_mapper);
+    for mount in extra_mounts {
+        options = options.add_boxed_mount(mount);
+    }
+    Ok((tmpdir, options))
+}
+
+/// Creates container with overlay over busybox rootfs.
+///
+/// See `test_container_options` for details.
+pub fn test_container(
+    cgroup: Cgroup,
+    extra_mounts: Vec<Box<dyn Mount>>,
+) -> Result<(TempDir, Container), Error> {
+    let (tmpdir, options) = test_container_options(cgroup, extra_mounts)?;
+    Ok((tmpdir, options.create()?))
+}
@@ -5,13 +5,14 @@ use std::io::{BufRead, BufReader};
 use std::panic::{catch_unwind, RefUnwindSafe, UnwindSafe};
 use std::process::Command;
 use std::str::FromStr;
+use std::sync::OnceLock;
 
 use nix::libc::uid_t;
 use nix::unistd::{getgid, getgrouplist, getuid, setgid, setgroups, setuid, User};
 
 use crate::{
-    clone3, exit_child, new_pipe, read_ok, read_result, write_ok, write_result, CloneArgs,
-    CloneResult, Error, OwnedPid, Pid,
+    clone3, exit_child, new_pipe, read_ok, read_result, set_child_panic_hook, write_ok,
+    write_result, CloneArgs, CloneResult, Error, OwnedPid, Pid,
 };
 
 pub type Uid = nix::unistd::Uid;
@@ -65,6 +66,7 @@ pub struct ProcUserMapper {
     pub uid_map: Vec<IdMap<Uid>>,
     pub gid_map: Vec<IdMap<Gid>>,
     pub set_groups: bool,
+    pub group_source: GroupSource,
 }
 
 impl ProcUserMapper {
@@ -74,6 +76,7 @@ impl ProcUserMapper {
             uid_map: vec![IdMap::new_root(uid)],
             gid_map: vec![IdMap::new_root(gid)],
             set_groups: false,
+            group_source: GroupSource::default(),
         }
     }
 }
@@ -94,11 +97,7 @@ impl UserMapper for ProcUserMapper {
     /// Sets user ID and group ID for current process in user namespace.
     fn set_user(&self, uid: Uid, gid: Gid) -> Result<(), Error> {
         if self.set_groups {
-            let groups = match User::from_uid(uid)? {
-                Some(user) => getgrouplist(&CString::new(user.name.as_bytes())?, gid)?,
-                None => vec![gid],
-            };
-            setgroups(&groups)?;
+            setgroups(&user_groups(self.group_source, uid, gid)?)?;
         }
         setgid(gid)?;
         Ok(setuid(uid)?)
@@ -125,6 +124,43 @@ impl UserMapper for ProcUserMapper {
     }
 }
 
+/// Represents user mapper of privileged containers without user namespace.
+///
+/// All IDs are represented by same IDs on host.
+#[derive(Clone, Debug)]
+pub(crate) struct HostUserMapper {
+    pub group_source: GroupSource,
+}
+
+impl UserMapper for HostUserMapper {
+    fn run_map_user(&self, _pid: Pid) -> Result<(), Error> {
+        Ok(())
+    }
+
+    fn set_user(&self, uid: Uid, gid: Gid) -> Result<(), Error> {
+        let groups = user_groups(self.group_source, uid, gid)?;
+        setgroups(&groups).map_err(|v| format!("Cannot set groups: {v}"))?;
+        setgid(gid).map_err(|v| format!("Cannot set group: {v}"))?;
+        Ok(setuid(uid).map_err(|v| format!("Cannot set user: {v}"))?)
+    }
+
+    fn is_uid_mapped(&self, _uid: Uid) -> bool {
+        true
+    }
+
+    fn is_gid_mapped(&self, _gid: Gid) -> bool {
+        true
+    }
+
+    fn uid_count(&self) -> u32 {
+        u32::MAX
+    }
+
+    fn gid_count(&self) -> u32 {
+        u32::MAX
+    }
+}
+
 /// Represents user mapper implemented using new{u,g}idmap.
 ///
 /// Uses new{u,g}idmap binaries from following paths:
@@ -136,6 +172,7 @@ pub struct BinNewIdMapper {
     pub gid_map: Vec<IdMap<Gid>>,
     pub uid_binary: String,
     pub gid_binary: String,
+    pub group_source: GroupSource,
 }
 
 impl BinNewIdMapper {
@@ -150,6 +187,7 @@ impl BinNewIdMapper {
             gid_map: vec![IdMap::new_root(gid)],
             uid_binary: "/bin/newuidmap".to_owned(),
             gid_binary: "/bin/newgidmap".to_owned(),
+            group_source: GroupSource::default(),
         }
     }
 
@@ -168,9 +206,62 @@ impl BinNewIdMapper {
             gid_map: Self::get_id_subid_map("/etc/subgid", gid, &user)?,
             uid_binary: "/bin/newuidmap".to_owned(),
             gid_binary: "/bin/newgidmap".to_owned(),
+            group_source: GroupSource::default(),
         })
     }
 
+    /// Returns amount of subordinate IDs required by mapping.
+    ///
+    /// IDs of current user and group are not subordinate, so they are not
+    /// counted. Result is maximum of required subuids and subgids.
+    pub fn required_subid_range(&self) -> u32 {
+        let uid = getuid();
+        let gid = getgid();
+        let uids = self.uid_map.iter().filter(|v| v.host_id != uid);
+        let gids = self.gid_map.iter().filter(|v| v.host_id != gid);
+        let uids: u32 = uids.map(|v| v.size).sum();
+        let gids: u32 = gids.map(|v| v.size).sum();
+        uids.max(gids)
+    }
+
+    /// Checks that user has at least specified amount of subuids and subgids.
+    ///
+    /// Ranges are read from `/etc/subuid` and `/etc/subgid`, entries can be
+    /// specified by user name or by user ID.
+    pub fn check_subid_available(user: &str, needed: u32) -> Result<(), Error> {
+        let uid = User::from_name(user)?
+            .ok_or(format!("Unknown user: {user}"))?
+            .uid;
+        for (path, kind) in [("/etc/subuid", "subuids"), ("/etc/subgid", "subgids")] {
+            let available = Self::count_subids(path, user, uid)?;
+            if available < needed {
+                return Err(format!(
+                    "User {user} has {available} {kind} but {needed} required, see {path}"
+                )
+                .into());
+            }
+        }
+        Ok(())
+    }
+
+    fn count_subids(path: &str, user: &str, uid: Uid) -> Result<u32, Error> {
+        let file = match File::open(path) {
+            Ok(v) => BufReader::new(v),
+            Err(v) if v.kind() == std::io::ErrorKind::NotFound => return Ok(0),
+            Err(v) => return Err(format!("Cannot read {path}: {v}").into()),
+        };
+        let uid = uid.to_string();
+        let mut count = 0u32;
+        for line in file.lines() {
+            let line = line?;
+            let parts: Vec<_> = line.split(':').collect();
+            if parts.len() >= 3 && (parts[0] == user || parts[0] == uid) {
+                count = count.saturating_add(u32::from_str(parts[2])?);
+            }
+        }
+        Ok(count)
+    }
+
     fn get_id_subid_map<T>(path: &str, id: T, user: &User) -> Result<Vec<IdMap<T>>, Error>
     where
         T: Copy + From<uid_t> + Into<uid_t>,
@@ -245,10 +336,7 @@ impl UserMapper for BinNewIdMapper {
 
     /// Sets user ID and group ID for current process in user namespace.
     fn set_user(&self, uid: Uid, gid: Gid) -> Result<(), Error> {
-        let groups = match User::from_uid(uid)? {
-            Some(user) => getgrouplist(&CString::new(user.name.as_bytes())?, gid)?,
-            None => vec![gid],
-        };
+        let groups = user_groups(self.group_source, uid, gid)?;
         setgroups(&groups).map_err(|v| format!("Cannot set groups: {v}"))?;
         setgid(gid).map_err(|v| format!("Cannot set group: {v}"))?;
         Ok(setuid(uid).map_err(|v| format!("Cannot set user: {v}"))?)
@@ -275,6 +363,66 @@ impl UserMapper for BinNewIdMapper {
     }
 }
 
+/// Represents source of supplementary groups for container users.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum GroupSource {
+    /// Resolves groups using libc NSS.
+    ///
+    /// NSS configuration and modules can be loaded from host before root is
+    /// changed, so groups can be resolved from host databases.
+    #[default]
+    Nss,
+    /// Parses `/etc/passwd` and `/etc/group` of container directly.
+    Files,
+}
+
+/// Resolves primary and supplementary groups of user.
+fn user_groups(source: GroupSource, uid: Uid, gid: Gid) -> Result<Vec<Gid>, Error> {
+    match source {
+        GroupSource::Nss => Ok(match User::from_uid(uid)? {
+            Some(user) => getgrouplist(&CString::new(user.name.as_bytes())?, gid)?,
+            None => vec![gid],
+        }),
+        GroupSource::Files => file_user_groups(uid, gid),
+    }
+}
+
+fn file_user_groups(uid: Uid, gid: Gid) -> Result<Vec<Gid>, Error> {
+    let mut groups = vec![gid];
+    let passwd = match std::fs::read_to_string("/etc/passwd") {
+        Ok(v) => v,
+        Err(v) if v.kind() == std::io::ErrorKind::NotFound => return Ok(groups),
+        Err(v) => return Err(format!("Cannot read /etc/passwd: {v}").into()),
+    };
+    let name = passwd.lines().find_map(|line| {
+        let parts: Vec<_> = line.split(':').collect();
+        match parts.get(2) {
+            Some(v) if *v == uid.to_string() => Some(parts[0].to_owned()),
+            _ => None,
+        }
+    });
+    let name = match name {
+        Some(v) => v,
+        None => return Ok(groups),
+    };
+    let group = match std::fs::read_to_string("/etc/group") {
+        Ok(v) => v,
+        Err(v) if v.kind() == std::io::ErrorKind::NotFound => return Ok(groups),
+        Err(v) => return Err(format!("Cannot read /etc/group: {v}").into()),
+    };
+    for line in group.lines() {
+        let parts: Vec<_> = line.split(':').collect();
+        if parts.len() < 4 || !parts[3].split(',').any(|v| v == name) {
+            continue;
+        }
+        let id = Gid::from_raw(parts[2].parse()?);
+        if !groups.contains(&id) {
+            groups.push(id);
+        }
+    }
+    Ok(groups)
+}
+
 pub fn run_as_user<
     T: UserMapper + RefUnwindSafe + ?Sized,
     Fn: FnOnce() -> Result<(), Error> + UnwindSafe,
@@ -284,6 +432,7 @@ pub fn run_as_user<
     gid: impl Into<Gid> + UnwindSafe,
     func: Fn,
 ) -> Result<(), Error> {
+    check_user_namespaces()?;
     let pipe = new_pipe()?;
     let child_pipe = new_pipe()?;
     let mut clone_args = CloneArgs::default();
@@ -291,8 +440,9 @@ pub fn run_as_user<
     match unsafe { clone3(&clone_args) }? {
         CloneResult::Child => {
             let _ = catch_unwind(move || {
-                let rx = pipe.rx();
                 let tx = child_pipe.tx();
+                set_child_panic_hook(Some(&tx));
+                let rx = pipe.rx();
                 exit_child(move || -> Result<(), Error> {
                     read_ok(rx)?;
                     write_result(
@@ -329,6 +479,38 @@ pub fn run_as_root<
     run_as_user(user_mapper, 0, 0, func)
 }
 
+/// Checks that current process can create user namespaces.
+///
+/// Sysctls are read once, so raw `EPERM` from clone is replaced with clear
+/// diagnosis on first use.
+pub(crate) fn check_user_namespaces() -> Result<(), Error> {
+    static ERROR: OnceLock<Option<String>> = OnceLock::new();
+    let read_sysctl =
+        |path: &str| -> Option<u64> { std::fs::read_to_string(path).ok()?.trim().parse().ok() };
+    let error = ERROR.get_or_init(|| {
+        if read_sysctl("/proc/sys/user/max_user_namespaces") == Some(0) {
+            return Some(
+                "user namespaces are disabled; set user.max_user_namespaces to non-zero value"
+                    .into(),
+            );
+        }
+        // Sysctl is available only on Debian and Ubuntu kernels.
+        if read_sysctl("/proc/sys/kernel/unprivileged_userns_clone") == Some(0)
+            && !Uid::effective().is_root()
+        {
+            return Some(
+                "unprivileged user namespaces are disabled; set kernel.unprivileged_userns_clone=1"
+                    .into(),
+            );
+        }
+        None
+    });
+    match error {
+        Some(v) => Err(v.as_str().into()),
+        None => Ok(()),
+    }
+}
+
 fn is_id_mapped<T>(id_map: &[IdMap<T>], id: T) -> bool
 where
     T: Copy + Into<uid_t>,
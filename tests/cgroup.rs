@@ -1,7 +1,15 @@
+use std::path::Path;
+
 use common::TempCgroup;
+use sbox::{Cgroup, Controllers, Pid};
 
 mod common;
 
+/// Mount path for tests of path logic, that do not touch filesystem.
+///
+/// Default mount path is not used since it requires host with cgroup v2.
+const MOUNT_PATH: &str = "/mnt/cgroup";
+
 #[test]
 fn test_cgroup() {
     let cgroup = TempCgroup::new().unwrap();
@@ -17,4 +25,78 @@ fn test_cgroup() {
         controllers.sort();
         assert_eq!(controllers, ["cpu", "memory", "pids"]);
     }
+    cgroup
+        .remove_subtree_controllers(vec!["cpu".into()])
+        .unwrap();
+    {
+        let mut controllers = cgroup.subtree_controllers().unwrap();
+        controllers.sort();
+        assert_eq!(controllers, ["memory", "pids"]);
+    }
+    {
+        let controllers = cgroup.subtree_controller_set().unwrap();
+        assert_eq!(controllers.to_string(), "memory pids");
+    }
+}
+
+#[test]
+fn test_cgroup_controllers() {
+    let controllers: Controllers = "memory pids\n".parse().unwrap();
+    assert_eq!(controllers, Controllers::from_iter(["pids", "memory"]));
+    assert!(controllers.contains("pids"));
+    let other: Controllers = "cpu pids".parse().unwrap();
+    assert_eq!(controllers.union(&other).to_string(), "cpu memory pids");
+    assert_eq!(controllers.intersection(&other).to_string(), "pids");
+    assert_eq!(controllers.difference(&other).to_string(), "memory");
+    assert!("".parse::<Controllers>().unwrap().is_empty());
+    assert!("+memory".parse::<Controllers>().is_err());
+    assert!("memory -pids".parse::<Controllers>().is_err());
+}
+
+#[test]
+fn test_cgroup_relative() {
+    let base = Cgroup::new(MOUNT_PATH, "sbox").unwrap();
+    let child = base.child("container/init").unwrap();
+    assert!(base.is_ancestor_of(&child));
+    assert!(!child.is_ancestor_of(&base));
+    assert!(!base.is_ancestor_of(&base));
+    assert_eq!(
+        child.relative_to(&base).unwrap(),
+        Path::new("container/init")
+    );
+    assert_eq!(base.relative_to(&child), None);
+    assert_eq!(child.parent().unwrap().parent().unwrap(), base);
+    assert_eq!(Cgroup::new("/mnt//cgroup/", "sbox/").unwrap(), base);
+    assert_ne!(Cgroup::new("/mnt/cgroup/sbox", "").unwrap(), base);
+}
+
+#[test]
+fn test_cgroup_escape() {
+    let cgroup = Cgroup::new(MOUNT_PATH, "sbox").unwrap();
+    assert!(cgroup.child("../escape").is_err());
+    assert!(cgroup.child("init/../../escape").is_err());
+    assert!(cgroup.child("./init").is_err());
+    assert!(Cgroup::new(MOUNT_PATH, "../escape").is_err());
+    assert!(cgroup.child("init").is_ok());
+}
+
+#[test]
+fn test_cgroup_move_process() {
+    let cgroup = TempCgroup::new().unwrap();
+    let from = cgroup.child("from").unwrap();
+    from.create().unwrap();
+    let to = cgroup.child("to").unwrap();
+    to.create().unwrap();
+    let mut child = std::process::Command::new("sleep")
+        .arg("10")
+        .spawn()
+        .unwrap();
+    let pid = Pid::from_raw(child.id() as i32);
+    from.add_process(pid).unwrap();
+    to.move_process_from(pid, &from).unwrap();
+    assert!(from.processes().unwrap().is_empty());
+    assert_eq!(to.processes().unwrap(), [pid]);
+    assert!(to.move_process_from(pid, &from).is_err());
+    child.kill().unwrap();
+    child.wait().unwrap();
 }
@@ -1,10 +1,17 @@
+use std::collections::VecDeque;
 use std::fs::{create_dir, remove_dir_all, File};
 use std::io::Read;
+use std::path::Path;
+use std::process::Command;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
-use common::{get_rootfs, TempCgroup, TempDir};
+use common::{get_rootfs, test_container_options, TempCgroup, TempDir};
 use sbox::{
-    run_as_root, BaseMounts, BinNewIdMapper, Container, Gid, InitProcess, OverlayMount, Process,
-    Slirp4NetnsManager, Uid,
+    run_as_root, AccessFs, BaseMounts, BinNewIdMapper, Container, FuseMount, Gid, InitProcess,
+    LandlockRuleset, OverlayMount, Process, ResourceLimits, Signal, Slirp4NetnsManager, Uid,
+    WaitStatus,
 };
 
 mod common;
@@ -40,6 +47,7 @@ fn test_container() {
         .network_manager(Slirp4NetnsManager::new())
         .create()
         .unwrap();
+    container.check_ownership(&rootfs_dir).unwrap();
     let mut init_process = InitProcess::options()
         .command(vec![
             "/bin/sh".into(),
@@ -131,6 +139,30 @@ fn test_container_stdio() {
     assert_eq!(stderr, "example stderr\n");
 }
 
+#[test]
+fn test_container_user() {
+    let cgroup = TempCgroup::new().unwrap();
+    let (tmpdir, options) = test_container_options(cgroup.clone(), Vec::new()).unwrap();
+    let container = options.hostname("sbox").create().unwrap();
+    let (rx, tx) = nix::unistd::pipe().unwrap();
+    let mut init_process = InitProcess::options()
+        .command(vec![
+            "/bin/sh".into(),
+            "-c".into(),
+            "id -u && id -g && hostname".into(),
+        ])
+        .user(Uid::from_raw(1000), Gid::from_raw(1000))
+        .stdout(tx)
+        .start(&container)
+        .unwrap();
+    let mut stdout = String::new();
+    File::from(rx).read_to_string(&mut stdout).unwrap();
+    init_process.wait().unwrap();
+    assert_eq!(stdout, "1000\n1000\nsbox\n");
+    let user_mapper = BinNewIdMapper::new_root_subid(Uid::current(), Gid::current()).unwrap();
+    run_as_root(&user_mapper, move || Ok(remove_dir_all(tmpdir.as_path())?)).unwrap();
+}
+
 #[test]
 fn test_container_oom() {
     let tmpdir = TempDir::new().unwrap();
@@ -182,3 +214,244 @@ fn test_container_oom() {
     let memory = cgroup.memory_peak().unwrap();
     assert!(memory >= 256 * 1024, "{memory}");
 }
+
+#[test]
+fn test_container_cgroup_limits() {
+    let cgroup = TempCgroup::new().unwrap();
+    let (_tmpdir, container) = common::test_container(cgroup.clone(), Vec::new()).unwrap();
+    let mut init_process = InitProcess::options()
+        .command(vec!["/bin/sleep".into(), "10".into()])
+        .cgroup("init")
+        .start(&container)
+        .unwrap();
+    assert!(cgroup.processes().unwrap().is_empty());
+    assert_eq!(
+        cgroup.child("init").unwrap().processes().unwrap(),
+        [init_process.as_pid()]
+    );
+    Process::options()
+        .command(vec!["/bin/true".into()])
+        .cgroup("worker")
+        .run(&container, &init_process)
+        .unwrap();
+    assert_eq!(cgroup.child("worker").unwrap().memory_max().unwrap(), None);
+    Process::options()
+        .command(vec!["/bin/true".into()])
+        .cgroup("limited")
+        .limits(ResourceLimits {
+            memory: Some(64 * 1024 * 1024),
+            ..Default::default()
+        })
+        .run(&container, &init_process)
+        .unwrap();
+    assert_eq!(
+        cgroup.child("limited").unwrap().memory_max().unwrap(),
+        Some(64 * 1024 * 1024)
+    );
+    nix::sys::signal::kill(init_process.as_pid(), nix::sys::signal::SIGKILL).unwrap();
+    init_process.wait().unwrap();
+}
+
+#[test]
+fn test_container_create_cleanup() {
+    let tmpdir = TempDir::new().unwrap();
+    let cgroup = TempCgroup::new().unwrap();
+    let container_cgroup = cgroup.child("container").unwrap();
+    let rootfs_dir = tmpdir.join("state/rootfs");
+    let user_mapper = BinNewIdMapper::new_root_subid(Uid::current(), Gid::current()).unwrap();
+    let result = Container::options()
+        .cgroup(container_cgroup.clone())
+        .rootfs(rootfs_dir)
+        .user_mapper(user_mapper)
+        .controllers(vec!["unknown".into()])
+        .create();
+    assert!(result.is_err());
+    assert!(!tmpdir.join("state").exists());
+    assert!(!container_cgroup.as_path().exists());
+}
+
+#[test]
+fn test_container_controllers() {
+    let cgroup = TempCgroup::new().unwrap();
+    let container_cgroup = cgroup.child("container").unwrap();
+    let (_tmpdir, options) = test_container_options(container_cgroup.clone(), Vec::new()).unwrap();
+    let mut container = options.controllers(vec!["pids".into()]).create().unwrap();
+    assert!(container_cgroup
+        .subtree_controller_set()
+        .unwrap()
+        .contains("pids"));
+    // Container cgroup with enabled controllers cannot contain processes.
+    assert!(container
+        .start(InitProcess::options().command(vec!["/bin/sleep".into(), "60".into()]))
+        .is_err());
+    container
+        .start(
+            InitProcess::options()
+                .command(vec!["/bin/sleep".into(), "60".into()])
+                .cgroup("init"),
+        )
+        .unwrap();
+    let output = Process::options()
+        .command(vec!["/bin/true".into()])
+        .cgroup("worker")
+        .limits(ResourceLimits {
+            pids: Some(16),
+            ..Default::default()
+        })
+        .run(&container, container.init_process().unwrap())
+        .unwrap();
+    assert!(
+        matches!(output.status, WaitStatus::Exited(_, 0)),
+        "{:?}",
+        output.status
+    );
+    container.stop().unwrap();
+}
+
+#[test]
+fn test_container_stop_reaps_processes() {
+    let cgroup = TempCgroup::new().unwrap();
+    let (_tmpdir, mut container) = common::test_container(cgroup.clone(), Vec::new()).unwrap();
+    container
+        .start(
+            InitProcess::options()
+                .command(vec!["/bin/sleep".into(), "60".into()])
+                .cgroup("init"),
+        )
+        .unwrap();
+    let process = Process::options()
+        .command(vec!["/bin/sleep".into(), "60".into()])
+        .cgroup("worker")
+        .start(&container, container.init_process().unwrap())
+        .unwrap();
+    container.stop().unwrap();
+    assert!(cgroup.processes_recursive().unwrap().is_empty());
+    let result = nix::sys::wait::waitpid(process.as_pid(), None);
+    assert_eq!(result, Err(nix::errno::Errno::ECHILD));
+    // Worker ignoring SIGTERM should not block graceful stop.
+    container
+        .start(
+            InitProcess::options()
+                .command(vec![
+                    "/bin/sh".into(),
+                    "-c".into(),
+                    "trap 'exit 3' TERM; touch /tmp/ready; while true; do sleep 0.1; done".into(),
+                ])
+                .cgroup("init"),
+        )
+        .unwrap();
+    Process::options()
+        .command(vec![
+            "/bin/sh".into(),
+            "-c".into(),
+            "while [ ! -e /tmp/ready ]; do sleep 0.1; done".into(),
+        ])
+        .cgroup("worker")
+        .run(&container, container.init_process().unwrap())
+        .unwrap();
+    Process::options()
+        .command(vec![
+            "/bin/sh".into(),
+            "-c".into(),
+            "trap '' TERM; sleep 60".into(),
+        ])
+        .cgroup("worker")
+        .start(&container, container.init_process().unwrap())
+        .unwrap();
+    let status = container.stop_with_signal(Signal::SIGTERM).unwrap();
+    assert!(matches!(status, WaitStatus::Exited(_, 3)), "{status:?}");
+    assert!(cgroup.processes_recursive().unwrap().is_empty());
+}
+
+#[test]
+fn test_container_concurrent_command() {
+    let cgroup = TempCgroup::new().unwrap();
+    let (_tmpdir, mut container) = common::test_container(cgroup.clone(), Vec::new()).unwrap();
+    // Commands spawned by other thread should not inherit start pipes.
+    let stop = Arc::new(AtomicBool::new(false));
+    let spawner = {
+        let stop = stop.clone();
+        std::thread::spawn(move || {
+            let mut children = VecDeque::new();
+            while !stop.load(Ordering::Relaxed) {
+                children.push_back(Command::new("sleep").arg("10").spawn().unwrap());
+                if children.len() > 8 {
+                    let mut child = children.pop_front().unwrap();
+                    child.kill().unwrap();
+                    child.wait().unwrap();
+                }
+                std::thread::sleep(Duration::from_millis(1));
+            }
+            children
+        })
+    };
+    container
+        .start(
+            InitProcess::options()
+                .command(vec!["/bin/sleep".into(), "60".into()])
+                .cgroup("init"),
+        )
+        .unwrap();
+    for _ in 0..100 {
+        let now = Instant::now();
+        Process::options()
+            .command(vec!["/bin/true".into()])
+            .cgroup("worker")
+            .run(&container, container.init_process().unwrap())
+            .unwrap();
+        assert!(now.elapsed() < Duration::from_secs(5));
+    }
+    stop.store(true, Ordering::Relaxed);
+    for mut child in spawner.join().unwrap() {
+        child.kill().unwrap();
+        child.wait().unwrap();
+    }
+    container.stop().unwrap();
+}
+
+#[test]
+fn test_container_fuse() {
+    let cgroup = TempCgroup::new().unwrap();
+    // Helper does not serve requests, so only mount itself is checked.
+    let fuse = FuseMount::new(
+        "/mnt/fuse",
+        vec!["/bin/sh".into(), "-c".into(), "sleep 60".into()],
+    )
+    .unwrap();
+    let (_tmpdir, mut container) =
+        common::test_container(cgroup.clone(), vec![Box::new(fuse)]).unwrap();
+    container
+        .start(InitProcess::options().command(vec!["/bin/sleep".into(), "60".into()]))
+        .unwrap();
+    let mounts = container.mounts_info().unwrap();
+    assert!(mounts
+        .iter()
+        .any(|v| v.fstype == "fuse" && v.mount_point == Path::new("/mnt/fuse")));
+    container.stop().unwrap();
+}
+
+#[test]
+fn test_container_landlock() {
+    let cgroup = TempCgroup::new().unwrap();
+    let (_tmpdir, mut container) = common::test_container(cgroup.clone(), Vec::new()).unwrap();
+    container
+        .start(InitProcess::options().command(vec!["/bin/sleep".into(), "60".into()]))
+        .unwrap();
+    // Rule for file with directory rights only has no effective rights and
+    // should be skipped instead of failing with ENOMSG.
+    let ruleset = LandlockRuleset::new()
+        .allow("/", AccessFs::READ)
+        .allow("/etc/passwd", AccessFs::READ_DIR)
+        .best_effort(true);
+    let output = Process::options()
+        .command(vec!["/bin/cat".into(), "/etc/passwd".into()])
+        .landlock(ruleset)
+        .run(&container, container.init_process().unwrap())
+        .unwrap();
+    assert!(
+        matches!(output.status, WaitStatus::Exited(_, 0)),
+        "{:?}",
+        output.status
+    );
+    container.stop().unwrap();
+}